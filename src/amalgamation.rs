@@ -0,0 +1,343 @@
+use crate::preprocessor::path_like::PathLike;
+use crate::preprocessor::{ForcedIncludeMode, ForcedIncludes, Line, Preprocessor};
+use crate::prune;
+use crate::tokenizer::{self, Token, TokenKind};
+use crate::Result;
+
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+/// Preprocessed source (output of `-E -dI`) of a translation unit
+#[derive(Debug)]
+pub struct Unit<'a> {
+    /// Base of relative paths in line markers
+    pub directory: &'a Path,
+    pub file: &'a Path,
+    pub preprocessed: &'a str,
+}
+
+/// Result of `merge()`
+#[derive(Debug, Default, Serialize)]
+pub struct Amalgamation {
+    #[serde(skip)]
+    pub text: String,
+    pub translation_units: Vec<PathBuf>,
+    /// Project headers, each emitted once with the translation unit first including it
+    pub headers: Vec<PathBuf>,
+    /// `#include` directives of system headers hoisted to the top, in order of first appearance
+    pub system_includes: Vec<String>,
+    /// Names of file-scope `static` symbols defined in more than one file, and the files
+    pub static_collisions: BTreeMap<String, Vec<PathBuf>>,
+}
+
+fn resolve(directory: &Path, path: &Path) -> PathBuf {
+    let path = directory.join(path);
+    path.canonicalize().unwrap_or(path)
+}
+
+/// Name of a declarator, i.e. the last identifier before it ends
+fn declarator_name<'a>(tokens: &[Token<'a>]) -> Option<&'a str> {
+    let mut name = None;
+    let mut i = 0;
+    while i < tokens.len() {
+        let token = tokens[i];
+        if token.is("__attribute__") || token.is("__declspec") {
+            match (i + 1 < tokens.len())
+                .then(|| tokenizer::matching_bracket(tokens, i + 1))
+                .flatten()
+            {
+                Some(close) => i = close + 1,
+                None => break,
+            }
+            continue;
+        }
+        if token.is("(") {
+            // Parenthesized declarator such as `(*handler)(int)`
+            if tokens.get(i + 1).is_some_and(|v| v.is("*")) {
+                if let Some(token) = tokens.get(i + 2) {
+                    if token.kind == TokenKind::Identifier {
+                        name = Some(token.text);
+                    }
+                }
+            }
+            break;
+        }
+        if token.is("=") || token.is("[") {
+            break;
+        }
+        if token.kind == TokenKind::Identifier {
+            name = Some(token.text);
+        }
+        i += 1;
+    }
+    name
+}
+
+/// Whether `tokens[open]` opens the body of a struct, union or enum
+fn is_record_body(tokens: &[Token], open: usize) -> bool {
+    let is_record = |v: &Token| v.is("struct") || v.is("union") || v.is("enum");
+    match open.checked_sub(1).map(|v| tokens[v]) {
+        Some(previous) if is_record(&previous) => true,
+        Some(previous) if previous.kind == TokenKind::Identifier => {
+            open >= 2 && is_record(&tokens[open - 2])
+        }
+        _ => false,
+    }
+}
+
+/// Names of `static` variables and functions declared at file scope in `source`
+fn file_scope_statics(source: &str) -> BTreeSet<String> {
+    let tokens = tokenizer::significant_tokens(source);
+    let mut names = BTreeSet::new();
+    let mut depth: usize = 0;
+    let mut i = 0;
+    while i < tokens.len() {
+        let token = tokens[i];
+        if token.is("{") {
+            depth += 1;
+        } else if token.is("}") {
+            depth = depth.saturating_sub(1);
+        } else if depth == 0 && token.is("static") {
+            // End of the declarators, i.e. `;` or `{` of a function body
+            let mut start = i + 1;
+            let mut end = i + 1;
+            let mut nested: usize = 0;
+            while let Some(token) = tokens.get(end) {
+                match token.text {
+                    "(" | "[" => nested += 1,
+                    // Braced initializer
+                    "{" if nested > 0 || tokens[end - 1].is("=") => nested += 1,
+                    // Body of a struct, union or enum, whose tag is not a declarator
+                    "{" if is_record_body(&tokens, end) => {
+                        match tokenizer::matching_bracket(&tokens, end) {
+                            Some(close) => {
+                                start = close + 1;
+                                end = close + 1;
+                                continue;
+                            }
+                            None => break,
+                        }
+                    }
+                    ")" | "]" | "}" => nested = nested.saturating_sub(1),
+                    ";" | "{" if nested == 0 => break,
+                    _ => (),
+                }
+                end += 1;
+            }
+            for declarator in prune::split_declarators(&tokens[start..end]) {
+                if let Some(name) = declarator_name(declarator) {
+                    names.insert(name.to_string());
+                }
+            }
+            // Continue from the end of the declarators so that `{` of a function body is counted
+            i = end;
+            continue;
+        }
+        i += 1;
+    }
+    names
+}
+
+/// Merge translation units into one source in the style of sqlite3.c.
+/// Line markers are stripped, and content of system headers is replaced with `#include`
+/// directives hoisted to the top.
+pub fn merge(units: &[Unit]) -> Result<Amalgamation> {
+    let mut amalgamation = Amalgamation::default();
+    let mut system_includes: HashSet<String> = HashSet::new();
+    // Index of the translation unit which emits each file
+    let mut owners: HashMap<PathBuf, usize> = HashMap::new();
+    let mut sections: BTreeMap<PathBuf, String> = BTreeMap::new();
+    let mut started: HashSet<PathBuf> = HashSet::new();
+    let mut body = String::new();
+    // Forced headers are inlined as well since the amalgamation is built without `-include`
+    let forced_includes = ForcedIncludes {
+        mode: ForcedIncludeMode::Inline,
+        ..Default::default()
+    };
+    for (index, unit) in units.iter().enumerate() {
+        amalgamation.translation_units.push(unit.file.to_path_buf());
+        let mut current: Option<PathBuf> = None;
+        for line in Preprocessor::annotate(unit.preprocessed, &forced_includes)? {
+            if !line.keep {
+                continue;
+            }
+            let context = match line.context {
+                Some(ref context) if !context.path().to_string_lossy().starts_with('<') => {
+                    resolve(unit.directory, context.path())
+                }
+                _ => continue,
+            };
+            match line.line {
+                Line::ExpandedHeader(_) => continue,
+                Line::IncludedHeader(_) => {
+                    let directive = line.text.trim().to_string();
+                    if system_includes.insert(directive.clone()) {
+                        amalgamation.system_includes.push(directive);
+                    }
+                    continue;
+                }
+                Line::Other(_) => (),
+            }
+
+            let owner = *owners.entry(context.clone()).or_insert_with(|| {
+                if context != unit.file {
+                    amalgamation.headers.push(context.clone());
+                }
+                index
+            });
+            if owner != index {
+                continue;
+            }
+            if current.as_ref() != Some(&context) {
+                let banner = if started.insert(context.clone()) {
+                    "Begin file"
+                } else {
+                    "Continuing where we left off in"
+                };
+                body.push_str(&format!(
+                    "/************** {} {} **************/\n",
+                    banner,
+                    context.display()
+                ));
+                current = Some(context.clone());
+            }
+            body.push_str(&line.text);
+            body.push('\n');
+            let section = sections.entry(context).or_default();
+            section.push_str(&line.text);
+            section.push('\n');
+        }
+    }
+
+    let mut definitions: BTreeMap<String, Vec<PathBuf>> = BTreeMap::new();
+    for (path, section) in sections.iter() {
+        for name in file_scope_statics(section) {
+            definitions.entry(name).or_default().push(path.clone());
+        }
+    }
+    definitions.retain(|_, files| files.len() > 1);
+    amalgamation.static_collisions = definitions;
+
+    amalgamation.text = format!(
+        "/*\n** Amalgamation of {} translation units generated by special-patch.\n*/\n",
+        units.len()
+    );
+    for directive in amalgamation.system_includes.iter() {
+        amalgamation.text.push_str(directive);
+        amalgamation.text.push('\n');
+    }
+    amalgamation.text.push_str(&body);
+    Ok(amalgamation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: &str = r#"# 0 "a.c"
+# 0 "<built-in>"
+# 0 "<command-line>"
+# 1 "a.c"
+#include <stdio.h>
+# 1 "a.c"
+# 1 "/usr/include/stdio.h" 1 3 4
+extern int printf(const char *, ...);
+# 2 "a.c" 2
+#include "common.h"
+# 2 "a.c"
+# 1 "common.h" 1
+static inline int inc(int a) { return a + 1; }
+# 3 "a.c" 2
+static int counter;
+int a(void) { return inc(counter); }
+"#;
+
+    const B: &str = r#"# 0 "b.c"
+# 1 "b.c"
+#include <stdlib.h>
+# 1 "b.c"
+# 1 "/usr/include/stdlib.h" 1 3 4
+extern void exit(int);
+# 2 "b.c" 2
+#include <stdio.h>
+# 2 "b.c"
+# 1 "/usr/include/stdio.h" 1 3 4
+extern int printf(const char *, ...);
+# 3 "b.c" 2
+#include "common.h"
+# 3 "b.c"
+# 1 "common.h" 1
+static inline int inc(int a) { return a + 1; }
+# 4 "b.c" 2
+static int counter = 1;
+int b(void) { return inc(counter); }
+"#;
+
+    #[test]
+    fn merge() {
+        let directory = Path::new("/nonexistent");
+        let units = [
+            Unit {
+                directory,
+                file: Path::new("/nonexistent/a.c"),
+                preprocessed: A,
+            },
+            Unit {
+                directory,
+                file: Path::new("/nonexistent/b.c"),
+                preprocessed: B,
+            },
+        ];
+        let amalgamation = super::merge(&units).unwrap();
+        assert_eq!(
+            amalgamation.system_includes,
+            vec!["#include <stdio.h>", "#include <stdlib.h>"]
+        );
+        assert_eq!(
+            amalgamation.headers,
+            vec![PathBuf::from("/nonexistent/common.h")]
+        );
+        assert_eq!(
+            amalgamation.text.matches("static inline int inc").count(),
+            1
+        );
+        assert!(!amalgamation.text.contains("extern int printf"));
+        assert!(!amalgamation.text.contains("# 1 "));
+        assert!(amalgamation.text.contains(
+            "/************** Begin file /nonexistent/common.h **************/\nstatic inline int inc(int a) { return a + 1; }\n/************** Begin file /nonexistent/a.c **************/\nstatic int counter;\n"
+        ));
+        assert_eq!(
+            amalgamation.static_collisions,
+            BTreeMap::from([(
+                String::from("counter"),
+                vec![
+                    PathBuf::from("/nonexistent/a.c"),
+                    PathBuf::from("/nonexistent/b.c")
+                ]
+            )])
+        );
+    }
+
+    #[test]
+    fn file_scope_statics() {
+        let source =
+            "static int a, b;\nstatic int c[2] = {0, 1}, d;\nstatic struct foo { int x; } s1, *s2;\nstatic enum { E1, E2 } e;\nstatic const char *s = \"x\";\nstatic void (*handler)(int);\n\
+            static void __attribute__((unused)) f(void) { static int local; }\nint g(void);\n";
+        assert_eq!(
+            super::file_scope_statics(source),
+            BTreeSet::from([
+                String::from("a"),
+                String::from("b"),
+                String::from("c"),
+                String::from("d"),
+                String::from("e"),
+                String::from("f"),
+                String::from("handler"),
+                String::from("s"),
+                String::from("s1"),
+                String::from("s2")
+            ])
+        );
+    }
+}
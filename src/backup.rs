@@ -0,0 +1,150 @@
+use crate::cache::{hash, hash_file};
+use log::warn;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+pub const DEFAULT_BACKUP_DIR: &str = ".special-patch/backup";
+
+/// Suffix of the file next to each backup which records the hash of the last patched content
+const PATCHED_HASH_SUFFIX: &str = ".patched-sha256";
+
+/// Original contents of patched files, mirroring their canonical paths under `dir`
+/// (e.g. `/src/repo/main.c` is kept as `<dir>/src/repo/main.c`).
+#[derive(Debug, Clone)]
+pub struct Backup {
+    dir: PathBuf,
+}
+
+impl Backup {
+    pub fn new(dir: &Path) -> Self {
+        Backup {
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    fn backup_path(&self, file: &Path) -> PathBuf {
+        let mut path = self.dir.clone();
+        path.extend(file.components().filter_map(|v| match v {
+            Component::Normal(v) => Some(v),
+            _ => None,
+        }));
+        path
+    }
+
+    fn patched_hash_path(backup_path: &Path) -> PathBuf {
+        let mut path = OsString::from(backup_path);
+        path.push(PATCHED_HASH_SUFFIX);
+        PathBuf::from(path)
+    }
+
+    /// Keep content of `file` before it is overwritten with `patched`.
+    /// The backup of an earlier run is kept while `file` still has the content written by the
+    /// last run, so that the backup is the content before the first run. Otherwise `file` was
+    /// changed since (e.g. by `git reset --hard`) and the backup is refreshed.
+    pub fn save(&self, file: &Path, patched: &str) -> io::Result<()> {
+        let path = self.backup_path(file);
+        let hash_path = Self::patched_hash_path(&path);
+        let stale = path.exists()
+            && match fs::read_to_string(&hash_path) {
+                Ok(last) => hash_file(file)? != last,
+                // Backup made by a version which did not record hashes
+                Err(_) => false,
+            };
+        if stale {
+            warn!(
+                "File was changed after the last patch, refreshing backup: file={:?}",
+                file
+            );
+        }
+        if !path.exists() || stale {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::copy(file, &path)?;
+        }
+        fs::write(hash_path, hash(patched.as_bytes()))?;
+        Ok(())
+    }
+
+    /// Restore `file` to the original and remove the backup.
+    /// Returns `false` if `file` has no backup.
+    pub fn restore(&self, file: &Path) -> io::Result<bool> {
+        let path = self.backup_path(file);
+        if !path.exists() {
+            return Ok(false);
+        }
+        fs::copy(&path, file)?;
+        fs::remove_file(&path)?;
+        let hash_path = Self::patched_hash_path(&path);
+        if hash_path.exists() {
+            fs::remove_file(hash_path)?;
+        }
+        Ok(true)
+    }
+
+    /// Canonical paths of files which have backups
+    pub fn files(&self) -> io::Result<Vec<PathBuf>> {
+        fn walk(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+            for entry in fs::read_dir(dir)? {
+                let path = entry?.path();
+                if path.is_dir() {
+                    walk(&path, files)?;
+                } else if !path.to_string_lossy().ends_with(PATCHED_HASH_SUFFIX) {
+                    files.push(path);
+                }
+            }
+            Ok(())
+        }
+
+        let mut files = Vec::new();
+        if self.dir.is_dir() {
+            walk(&self.dir, &mut files)?;
+        }
+        let mut files: Vec<PathBuf> = files
+            .into_iter()
+            .filter_map(|v| Some(Path::new("/").join(v.strip_prefix(&self.dir).ok()?)))
+            .collect();
+        files.sort();
+        Ok(files)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_and_restore() {
+        let root =
+            std::env::temp_dir().join(format!("special-patch-backup-{}", std::process::id()));
+        fs::create_dir_all(root.join("src")).unwrap();
+        let root = root.canonicalize().unwrap();
+        let file = root.join("src/main.c");
+        fs::write(&file, "original").unwrap();
+
+        let backup = Backup::new(&root.join("backup"));
+        backup.save(&file, "patched").unwrap();
+        fs::write(&file, "patched").unwrap();
+        // Backup of the first run is kept
+        backup.save(&file, "patched again").unwrap();
+        fs::write(&file, "patched again").unwrap();
+        assert_eq!(backup.files().unwrap(), vec![file.clone()]);
+        assert!(backup.restore(&file).unwrap());
+        assert_eq!(fs::read_to_string(&file).unwrap(), "original");
+
+        // Backup is refreshed if the file is changed after the last run
+        backup.save(&file, "patched").unwrap();
+        fs::write(&file, "updated").unwrap();
+        backup.save(&file, "patched").unwrap();
+        fs::write(&file, "patched").unwrap();
+        assert_eq!(backup.files().unwrap(), vec![file.clone()]);
+
+        assert!(backup.restore(&file).unwrap());
+        assert_eq!(fs::read_to_string(&file).unwrap(), "updated");
+        assert!(!backup.restore(&file).unwrap());
+        assert!(backup.files().unwrap().is_empty());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,136 @@
+use crate::compile_command::CompileCommand;
+use crate::edit::{self, Edit};
+use crate::language::FileType;
+use crate::patch::{self, RuleConfig, RuleEdit};
+use crate::Result;
+
+#[allow(unused_imports)]
+use log::{error, info, trace, warn};
+use std::fs;
+use std::path::Path;
+
+#[derive(Debug)]
+pub enum BisectOutcome {
+    /// The file does not compile even without edits
+    OriginalFails { diagnostics: String },
+    /// The file compiles with all of `edits`
+    Compiles { edits: usize },
+    /// `edits` out of `total` edits break compilation
+    Minimal {
+        total: usize,
+        edits: Vec<RuleEdit>,
+        diagnostics: String,
+    },
+}
+
+/// Minimize `items` to a subset on which `fails` still holds, by the ddmin algorithm
+/// (Zeller and Hildebrandt, "Simplifying and Isolating Failure-Inducing Input").
+/// `fails(items)` is assumed to be true.
+pub fn ddmin<T: Clone>(items: Vec<T>, mut fails: impl FnMut(&[T]) -> bool) -> Vec<T> {
+    let mut items = items;
+    let mut granularity = 2;
+    while items.len() >= 2 {
+        let chunk_size = items.len().div_ceil(granularity);
+        let chunks: Vec<Vec<T>> = items.chunks(chunk_size).map(|v| v.to_vec()).collect();
+
+        // Reduce to a subset
+        if let Some(chunk) = chunks.iter().find(|v| fails(v)) {
+            items = chunk.clone();
+            granularity = 2;
+            continue;
+        }
+
+        // Reduce to a complement
+        let complements = (0..chunks.len()).map(|i| {
+            chunks
+                .iter()
+                .enumerate()
+                .filter(|(j, _)| *j != i)
+                .flat_map(|(_, v)| v.iter().cloned())
+                .collect::<Vec<T>>()
+        });
+        if chunks.len() > 2 {
+            if let Some(complement) = complements.into_iter().find(|v| fails(v)) {
+                items = complement;
+                granularity = (granularity - 1).max(2);
+                continue;
+            }
+        }
+
+        // Increase granularity
+        if granularity >= items.len() {
+            break;
+        }
+        granularity = (granularity * 2).min(items.len());
+    }
+    items
+}
+
+/// Find the minimal set of rule edits in `file` which makes `command` fail to compile.
+/// `file` is restored to the original afterwards.
+pub fn bisect(
+    command: &CompileCommand,
+    file: &Path,
+    file_type: Option<FileType>,
+    config: &RuleConfig,
+) -> Result<BisectOutcome> {
+    let original = fs::read_to_string(file)?;
+    let edits = patch::all_rule_edits(&original, file_type, config);
+    let check = |indices: &[usize]| -> Result<Option<String>> {
+        let subset: Vec<Edit> = indices.iter().map(|i| edits[*i].1.clone()).collect();
+        fs::write(file, edit::apply(&original, &subset))?;
+        command.check_syntax()
+    };
+    let fails = |indices: &[usize]| {
+        check(indices)
+            .unwrap_or_else(|err| {
+                error!("Failed to run compile command: err={:?}", err);
+                None
+            })
+            .is_some()
+    };
+
+    let all: Vec<usize> = (0..edits.len()).collect();
+    let result = (|| {
+        if let Some(diagnostics) = check(&[])? {
+            return Ok(BisectOutcome::OriginalFails { diagnostics });
+        }
+        if !fails(&all) {
+            return Ok(BisectOutcome::Compiles { edits: edits.len() });
+        }
+        let minimal = ddmin(all, fails);
+        let diagnostics = check(&minimal)?.unwrap_or_default();
+        let minimal = minimal
+            .iter()
+            .map(|i| {
+                let (rule, edit) = &edits[*i];
+                RuleEdit::new(&original, *rule, edit)
+            })
+            .collect();
+        Ok(BisectOutcome::Minimal {
+            total: edits.len(),
+            edits: minimal,
+            diagnostics,
+        })
+    })();
+    fs::write(file, &original)?;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn ddmin() {
+        let items: Vec<usize> = (0..20).collect();
+        let mut tests = 0;
+        let minimal = super::ddmin(items, |v| {
+            tests += 1;
+            v.contains(&3) && v.contains(&17)
+        });
+        assert_eq!(minimal, vec![3, 17]);
+        assert!(tests < 60);
+
+        assert_eq!(super::ddmin(vec![1, 2, 3], |v| v.contains(&2)), vec![2]);
+        assert_eq!(super::ddmin(vec![1], |_| true), vec![1]);
+    }
+}
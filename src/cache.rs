@@ -0,0 +1,156 @@
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_CACHE_PATH: &str = ".special-patch/cache.json";
+
+/// State of a file recorded after it was patched.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CacheEntry {
+    /// Hash of the content after patching
+    pub hash: String,
+    /// Arguments of the compile command, if the file is a translation unit
+    #[serde(default)]
+    pub arguments: Option<Vec<String>>,
+    /// Tool version and enabled rules
+    pub fingerprint: String,
+    /// Headers discovered from the translation unit
+    #[serde(default)]
+    pub headers: Vec<PathBuf>,
+}
+
+/// Content-hash cache for incremental mode, keyed on canonical file paths.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Cache {
+    entries: HashMap<PathBuf, CacheEntry>,
+}
+
+impl Cache {
+    /// Returns an empty cache if the cache file does not exist or is broken.
+    pub fn load(path: &Path) -> Self {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(_) => return Cache::default(),
+        };
+        serde_json::from_str(&content).unwrap_or_else(|err| {
+            warn!("Ignore broken cache: path={:?}, err={:?}", path, err);
+            Cache::default()
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, serde_json::to_string_pretty(self)?)
+    }
+
+    /// Returns the entry if `file` has not changed since it was patched with same arguments and rules.
+    /// Entries of translation units are fresh only if all headers discovered from them are fresh too.
+    pub fn fresh_entry(
+        &self,
+        file: &Path,
+        arguments: Option<&Vec<String>>,
+        fingerprint: &str,
+    ) -> Option<&CacheEntry> {
+        let entry = self.entries.get(file)?;
+        if entry.fingerprint != fingerprint || entry.arguments.as_ref() != arguments {
+            return None;
+        }
+        if hash_file(file).ok()? != entry.hash {
+            return None;
+        }
+        if !entry
+            .headers
+            .iter()
+            .all(|v| self.fresh_entry(v, None, fingerprint).is_some())
+        {
+            return None;
+        }
+        Some(entry)
+    }
+
+    pub fn insert(&mut self, file: PathBuf, entry: CacheEntry) {
+        self.entries.insert(file, entry);
+    }
+}
+
+pub fn hash(content: &[u8]) -> String {
+    Sha256::digest(content)
+        .iter()
+        .map(|v| format!("{:02x}", v))
+        .collect()
+}
+
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    Ok(hash(&fs::read(path)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash() {
+        assert_eq!(
+            super::hash(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+
+    #[test]
+    fn fresh_entry() {
+        let file = Path::new("./test/preprocessor/main.c")
+            .canonicalize()
+            .unwrap();
+        let header = Path::new("./test/preprocessor/calc.h")
+            .canonicalize()
+            .unwrap();
+        let arguments = vec![
+            String::from("cc"),
+            String::from("-c"),
+            String::from("main.c"),
+        ];
+        let mut cache = Cache::default();
+        cache.insert(
+            file.clone(),
+            CacheEntry {
+                hash: hash_file(&file).unwrap(),
+                arguments: Some(arguments.clone()),
+                fingerprint: String::from("v1"),
+                headers: vec![header.clone()],
+            },
+        );
+        // Stale header makes the translation unit stale
+        assert!(cache.fresh_entry(&file, Some(&arguments), "v1").is_none());
+
+        cache.insert(
+            header.clone(),
+            CacheEntry {
+                hash: hash_file(&header).unwrap(),
+                arguments: None,
+                fingerprint: String::from("v1"),
+                headers: vec![],
+            },
+        );
+        assert!(cache.fresh_entry(&file, Some(&arguments), "v1").is_some());
+        assert!(cache.fresh_entry(&file, Some(&arguments), "v2").is_none());
+        assert!(cache.fresh_entry(&file, None, "v1").is_none());
+
+        cache.insert(
+            header.clone(),
+            CacheEntry {
+                hash: super::hash(b"changed"),
+                arguments: None,
+                fingerprint: String::from("v1"),
+                headers: vec![],
+            },
+        );
+        assert!(cache.fresh_entry(&header, None, "v1").is_none());
+        assert!(cache.fresh_entry(&file, Some(&arguments), "v1").is_none());
+    }
+}
@@ -0,0 +1,466 @@
+//! Insertion of canaries at locations given by a spec file.
+//!
+//! ```yaml
+//! includes: [canary.h]
+//! canaries:
+//!   - file: pngrtran.c
+//!     line: 120
+//!     context: "if (png_ptr == NULL)"
+//!     snippet: MAGMA_LOG("PNG001", png_ptr == NULL);
+//!   - file: pngrutil.c
+//!     function: png_handle_IHDR
+//!     snippet: MAGMA_LOG("PNG002", length != 13);
+//! ```
+
+use crate::edit::{self, Edit};
+use crate::injection::{self, IncludeStyle, Placement};
+use crate::probe;
+use crate::{Error, Result};
+
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Where to insert a snippet relative to the located line
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CanaryPosition {
+    #[default]
+    Before,
+    After,
+}
+
+/// Snippet and its location. Exactly one of `line`, `function` and `pattern` is given.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CanaryEntry {
+    /// Relative paths are resolved against the directory of the spec
+    pub file: PathBuf,
+    /// Line number starting from 1
+    pub line: Option<usize>,
+    /// Function whose body starts with the snippet
+    pub function: Option<String>,
+    /// Regex matching exactly one line
+    pub pattern: Option<String>,
+    /// Text which the line at `line` contains
+    pub context: Option<String>,
+    pub snippet: String,
+    /// Not available for `function`
+    #[serde(default)]
+    pub position: CanaryPosition,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CanarySpec {
+    /// Headers included by files with canaries, in the same way as `--include`
+    #[serde(default)]
+    pub includes: Vec<String>,
+    pub canaries: Vec<CanaryEntry>,
+}
+
+/// Content of a file with canaries inserted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CanaryFile {
+    pub text: String,
+    /// Canaries inserted. Ones already in the file are not counted.
+    pub inserted: usize,
+}
+
+fn line_start(source: &str, line: usize) -> usize {
+    if line == 0 {
+        return 0;
+    }
+    source
+        .match_indices('\n')
+        .nth(line - 1)
+        .map_or(source.len(), |(i, _)| i + 1)
+}
+
+fn indentation(line: &str) -> &str {
+    &line[..line.len() - line.trim_start().len()]
+}
+
+/// Whether `snippet` is among the canaries adjacent to a line, walking from `index` forward or
+/// backward until a line which is not one of `snippets`
+fn has_adjacent_snippet(
+    lines: &[&str],
+    mut index: Option<usize>,
+    forward: bool,
+    snippet: &str,
+    snippets: &HashSet<&str>,
+) -> bool {
+    while let Some(i) = index.filter(|v| *v < lines.len()) {
+        let line = lines[i].trim();
+        if !snippets.contains(line) {
+            return false;
+        }
+        if line == snippet {
+            return true;
+        }
+        index = if forward {
+            i.checked_add(1)
+        } else {
+            i.checked_sub(1)
+        };
+    }
+    false
+}
+
+impl CanaryEntry {
+    /// Edit inserting the snippet into `source`, or `None` if the snippet is already at the
+    /// location. Lines which are one of `snippets` (i.e. canaries of the file) are skipped in
+    /// locating lines, so that lines are numbered as in the file without canaries.
+    fn edit(
+        &self,
+        source: &str,
+        snippets: &HashSet<&str>,
+    ) -> std::result::Result<Option<Edit>, String> {
+        let snippet = self.snippet.trim();
+        if snippet.is_empty() {
+            return Err(String::from("snippet is empty"));
+        }
+        let locators = [
+            self.line.is_some(),
+            self.function.is_some(),
+            self.pattern.is_some(),
+        ];
+        if locators.iter().filter(|v| **v).count() != 1 {
+            return Err(String::from(
+                "exactly one of line, function and pattern is required",
+            ));
+        }
+        let lines: Vec<&str> = source.lines().collect();
+        // Indices of lines which are not canaries
+        let original: Vec<usize> = (0..lines.len())
+            .filter(|i| !snippets.contains(lines[*i].trim()))
+            .collect();
+        let line = match (&self.line, &self.function, &self.pattern) {
+            (Some(line), None, None) => {
+                if *line == 0 || *line > original.len() {
+                    return Err(format!(
+                        "line {} is out of range (the file has {} lines)",
+                        line,
+                        original.len()
+                    ));
+                }
+                original[*line - 1]
+            }
+            (None, Some(function), None) => {
+                if self.position != CanaryPosition::Before {
+                    return Err(String::from("position is not available for function"));
+                }
+                let definitions: Vec<_> = probe::function_definitions(source)
+                    .into_iter()
+                    .filter(|v| v.name == function)
+                    .collect();
+                let body = match definitions[..] {
+                    [ref definition] => definition.body,
+                    [] => return Err(format!("function {} is not defined", function)),
+                    _ => {
+                        return Err(format!(
+                            "function {} is defined {} times",
+                            function,
+                            definitions.len()
+                        ))
+                    }
+                };
+                let line_end = source[body..].find('\n').map_or(source.len(), |v| body + v);
+                let rest = source[body + 1..line_end].trim();
+                let body_line = source[..body].matches('\n').count();
+                if rest.starts_with(snippet)
+                    || (rest.is_empty()
+                        && has_adjacent_snippet(
+                            &lines,
+                            Some(body_line + 1),
+                            true,
+                            snippet,
+                            snippets,
+                        ))
+                {
+                    return Ok(None);
+                }
+                if !rest.is_empty() {
+                    // Code follows `{` in the same line
+                    return Ok(Some(Edit::new(
+                        body + 1..body + 1,
+                        &format!(" {}", snippet),
+                    )));
+                }
+                let next = source.get(line_end + 1..).unwrap_or_default();
+                let indent = match next.lines().next() {
+                    Some(v) if !v.trim().is_empty() && v.trim() != "}" => {
+                        indentation(v).to_string()
+                    }
+                    _ => {
+                        let line = source[..body].rfind('\n').map_or(0, |v| v + 1);
+                        format!("{}    ", indentation(&source[line..body]))
+                    }
+                };
+                let offset = (line_end + 1).min(source.len());
+                let newline = if offset == source.len() { "\n" } else { "" };
+                return Ok(Some(Edit::new(
+                    offset..offset,
+                    &format!("{}{}{}\n", newline, indent, snippet),
+                )));
+            }
+            (None, None, Some(pattern)) => {
+                let re = Regex::new(pattern).map_err(|err| err.to_string())?;
+                let matched: Vec<usize> = original
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, v)| re.is_match(lines[**v]))
+                    .map(|(i, _)| i)
+                    .collect();
+                match matched[..] {
+                    [line] => original[line],
+                    [] => return Err(format!("pattern {:?} matches no line", pattern)),
+                    _ => {
+                        return Err(format!(
+                            "pattern {:?} matches {} lines: {:?}",
+                            pattern,
+                            matched.len(),
+                            matched.iter().map(|v| v + 1).collect::<Vec<_>>()
+                        ))
+                    }
+                }
+            }
+            _ => unreachable!(),
+        };
+        if let Some(ref context) = self.context {
+            if !lines[line].contains(context.as_str()) {
+                return Err(format!(
+                    "line {} does not contain {:?}: {:?}",
+                    self.line.unwrap_or(line + 1),
+                    context,
+                    lines[line]
+                ));
+            }
+        }
+        let inserted = match self.position {
+            CanaryPosition::Before => {
+                has_adjacent_snippet(&lines, line.checked_sub(1), false, snippet, snippets)
+            }
+            CanaryPosition::After => {
+                has_adjacent_snippet(&lines, Some(line + 1), true, snippet, snippets)
+            }
+        };
+        if inserted {
+            return Ok(None);
+        }
+        let (offset, newline) = match self.position {
+            CanaryPosition::Before => (line_start(source, line), ""),
+            CanaryPosition::After => {
+                let offset = line_start(source, line + 1);
+                (
+                    offset,
+                    if source[..offset].ends_with('\n') {
+                        ""
+                    } else {
+                        "\n"
+                    },
+                )
+            }
+        };
+        Ok(Some(Edit::new(
+            offset..offset,
+            &format!("{}{}{}\n", newline, indentation(lines[line]), snippet),
+        )))
+    }
+}
+
+impl CanarySpec {
+    /// Load a spec in YAML, or in JSON if the extension is `.json`.
+    /// Paths of files are resolved against the directory of the spec and canonicalized.
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)?;
+        let mut spec: CanarySpec = if path.extension().is_some_and(|v| v == "json") {
+            serde_json::from_str(&content)?
+        } else {
+            serde_yaml::from_str(&content)?
+        };
+        let base = path.parent().unwrap_or(Path::new(""));
+        for entry in spec.canaries.iter_mut() {
+            let file = base.join(&entry.file);
+            entry.file = file
+                .canonicalize()
+                .map_err(|err| Error::CanarySpecError(format!("{:?}: {}", file, err)))?;
+        }
+        Ok(spec)
+    }
+
+    /// Files which have canaries
+    pub fn files(&self) -> Vec<&Path> {
+        let mut files: Vec<&Path> = self.canaries.iter().map(|v| v.file.as_path()).collect();
+        files.sort();
+        files.dedup();
+        files
+    }
+
+    /// Insert canaries and `includes` into files. All entries are validated against the files
+    /// first, and errors of all invalid entries are reported together.
+    pub fn apply(
+        &self,
+        style: IncludeStyle,
+        placement: Placement,
+    ) -> Result<BTreeMap<PathBuf, CanaryFile>> {
+        let mut errors = Vec::new();
+        let mut result = BTreeMap::new();
+        for file in self.files() {
+            let source = fs::read_to_string(file)?;
+            let snippets: HashSet<&str> = self
+                .canaries
+                .iter()
+                .filter(|v| v.file == file)
+                .map(|v| v.snippet.trim())
+                .collect();
+            let mut edits = Vec::new();
+            for (index, entry) in self.canaries.iter().enumerate() {
+                if entry.file != file {
+                    continue;
+                }
+                match entry.edit(&source, &snippets) {
+                    Ok(v) => edits.extend(v),
+                    Err(err) => errors.push(format!("canaries[{}] ({:?}): {}", index, file, err)),
+                }
+            }
+            // Keep the order of entries inserted at the same offset
+            edits.sort_by_key(|v| v.range.start);
+            let mut text = edit::apply(&source, &edits);
+            if let Some(patched) =
+                injection::insert_includes(&text, &self.includes, style, placement)
+            {
+                text = patched;
+            }
+            result.insert(
+                file.to_path_buf(),
+                CanaryFile {
+                    text,
+                    inserted: edits.len(),
+                },
+            );
+        }
+        if !errors.is_empty() {
+            return Err(Error::CanarySpecError(errors.join("\n")));
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(snippet: &str) -> CanaryEntry {
+        CanaryEntry {
+            file: PathBuf::from("a.c"),
+            line: None,
+            function: None,
+            pattern: None,
+            context: None,
+            snippet: String::from(snippet),
+            position: CanaryPosition::Before,
+        }
+    }
+
+    #[test]
+    fn edit() {
+        let source = "int f(int a)\n{\n    if (a)\n        return 1;\n    return 0;\n}\nint g(void) { return 0; }\n";
+        let snippets = HashSet::from(["LOG(1, a);", "LOG(2, 0);", "LOG(3, 0);", "LOG(4, 0);"]);
+        let apply = |entry: &CanaryEntry| {
+            edit::apply(
+                source,
+                &entry
+                    .edit(source, &snippets)
+                    .unwrap()
+                    .into_iter()
+                    .collect::<Vec<_>>(),
+            )
+        };
+
+        let by_line = CanaryEntry {
+            line: Some(3),
+            context: Some(String::from("if (a)")),
+            ..entry("LOG(1, a);")
+        };
+        assert_eq!(
+            apply(&by_line),
+            "int f(int a)\n{\n    LOG(1, a);\n    if (a)\n        return 1;\n    return 0;\n}\nint g(void) { return 0; }\n"
+        );
+        let by_pattern = CanaryEntry {
+            pattern: Some(String::from(r"return 1;")),
+            position: CanaryPosition::After,
+            ..entry("LOG(2, 0);")
+        };
+        assert!(apply(&by_pattern).contains("        return 1;\n        LOG(2, 0);\n"));
+        let by_function = CanaryEntry {
+            function: Some(String::from("f")),
+            ..entry("LOG(3, 0);")
+        };
+        assert!(apply(&by_function).starts_with("int f(int a)\n{\n    LOG(3, 0);\n    if (a)\n"));
+        let same_line = CanaryEntry {
+            function: Some(String::from("g")),
+            ..entry("LOG(4, 0);")
+        };
+        assert!(apply(&same_line).ends_with("int g(void) { LOG(4, 0); return 0; }\n"));
+
+        // Already inserted, with lines numbered as in the file without canaries
+        let patched = apply(&by_line);
+        assert_eq!(by_line.edit(&patched, &snippets), Ok(None));
+        for entry in [&by_pattern, &by_function, &same_line] {
+            assert_eq!(entry.edit(&apply(entry), &snippets), Ok(None));
+            assert!(entry.edit(&patched, &snippets).unwrap().is_some());
+        }
+        // The same snippet at another location
+        let again = CanaryEntry {
+            line: Some(5),
+            context: Some(String::from("return 0;")),
+            ..entry("LOG(1, a);")
+        };
+        let edit = again.edit(&patched, &snippets).unwrap().unwrap();
+        let patched = edit::apply(&patched, &[edit]);
+        assert!(patched.contains("        return 1;\n    LOG(1, a);\n    return 0;\n"));
+        assert_eq!(by_line.edit(&patched, &snippets), Ok(None));
+        assert_eq!(again.edit(&patched, &snippets), Ok(None));
+
+        // Stale entries
+        let stale = CanaryEntry {
+            line: Some(4),
+            context: Some(String::from("if (a)")),
+            ..entry("LOG(1, a);")
+        };
+        assert!(stale.edit(source, &snippets).is_err());
+        assert!(stale.edit(&patched, &snippets).is_err());
+        let ambiguous = CanaryEntry {
+            pattern: Some(String::from("return")),
+            ..entry("LOG(1, a);")
+        };
+        assert_eq!(
+            ambiguous.edit(source, &snippets),
+            Err(String::from(
+                "pattern \"return\" matches 3 lines: [4, 5, 7]"
+            ))
+        );
+        assert!(CanaryEntry {
+            line: Some(8),
+            ..entry("LOG(1, a);")
+        }
+        .edit(source, &snippets)
+        .is_err());
+        assert!(CanaryEntry {
+            function: Some(String::from("h")),
+            ..entry("LOG(1, a);")
+        }
+        .edit(source, &snippets)
+        .is_err());
+        assert!(CanaryEntry {
+            line: Some(1),
+            function: Some(String::from("f")),
+            ..entry("LOG(1, a);")
+        }
+        .edit(source, &snippets)
+        .is_err());
+    }
+}
@@ -0,0 +1,510 @@
+use crate::language::{FileType, LanguageMap};
+use crate::preprocessor::include_graph::IncludeGraph;
+use crate::preprocessor::{ForcedIncludeMode, ForcedIncludes, PreprocessStats, Preprocessor};
+use crate::{Error, Result};
+
+#[allow(unused_imports)]
+use log::{error, info, trace, warn};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Name of the file which line markers of the header protecting macros are renamed to
+const PROTECTED_MACROS_FILE: &str = "<protected-macros>";
+
+/// Counter making names of temporary headers unique in the process
+static TEMPORARY_HEADERS: AtomicUsize = AtomicUsize::new(0);
+
+/// Entry of compile_commands.json
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CompileCommand {
+    pub directory: PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub arguments: Option<Vec<String>>,
+    pub file: PathBuf,
+}
+
+/// `Error::ExitStatusError` unless `status` is success
+fn exit_ok(status: ExitStatus) -> Result<()> {
+    if status.success() {
+        Ok(())
+    } else {
+        Err(Error::ExitStatusError(status))
+    }
+}
+
+/// Header forced by `-include` or `-imacros`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForcedInclude {
+    /// As written in the arguments
+    pub path: PathBuf,
+    pub imacros: bool,
+    /// Indices of the arguments giving the header
+    arguments: Range<usize>,
+}
+
+impl CompileCommand {
+    /// Load compile_commands.json. Paths of entries are resolved by `CompileCommand::resolve()`,
+    /// and entries which cannot be resolved are skipped.
+    pub fn load(path: &Path) -> Result<Vec<Self>> {
+        let compile_commands: Vec<CompileCommand> =
+            serde_json::from_str(&fs::read_to_string(path)?)?;
+
+        // Resolve relative paths once so that dedup, ignore and writing see the same paths
+        let base = path
+            .parent()
+            .filter(|v| !v.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new("."));
+        Ok(compile_commands
+            .into_iter()
+            .filter_map(|command| {
+                let file = command.file.clone();
+                command
+                    .resolve(base)
+                    .map_err(|err| {
+                        warn!(
+                            "Failed to resolve path. Skip: file={:?}, err={:?}",
+                            file, err
+                        )
+                    })
+                    .ok()
+            })
+            .collect())
+    }
+
+    /// Normalize `directory` and `file` into canonical absolute paths.
+    /// A relative `directory` is resolved against `base` (the directory of compile_commands.json),
+    /// and a relative `file` is resolved against `directory`.
+    pub fn resolve(mut self, base: &Path) -> io::Result<Self> {
+        self.directory = base.join(&self.directory).canonicalize()?;
+        self.file = self.directory.join(&self.file).canonicalize()?;
+        Ok(self)
+    }
+
+    pub fn arguments(&self) -> Result<Vec<String>> {
+        if let Some(ref arguments) = self.arguments {
+            Ok(arguments.clone())
+        } else if let Some(ref command) = self.command {
+            Ok(shell_words::split(command)?)
+        } else {
+            Err(Error::CommandFormatError)
+        }
+    }
+
+    /// Headers given by `-include` and `-imacros` (also `--include`, `-include=` and
+    /// `-includeFILE` forms), in order of the arguments
+    pub fn forced_includes(&self) -> Result<Vec<ForcedInclude>> {
+        let args = self.arguments()?;
+        let mut result = Vec::new();
+        let mut i = 0;
+        while i < args.len() {
+            let arg = &args[i];
+            let mut found = None;
+            for (flag, imacros) in [("include", false), ("imacros", true)] {
+                let value = if *arg == format!("-{}", flag) || *arg == format!("--{}", flag) {
+                    args.get(i + 1).map(|v| (v.as_str(), 2))
+                } else if let Some(v) = arg.strip_prefix(&format!("--{}=", flag)) {
+                    Some((v, 1))
+                } else {
+                    // Not `-include-pch`
+                    arg.strip_prefix(&format!("-{}", flag))
+                        .filter(|v| !v.is_empty() && !v.starts_with('-'))
+                        .map(|v| (v, 1))
+                };
+                if let Some((path, count)) = value {
+                    found = Some(ForcedInclude {
+                        path: PathBuf::from(path),
+                        imacros,
+                        arguments: i..i + count,
+                    });
+                    break;
+                }
+            }
+            match found {
+                Some(forced_include) => {
+                    i = forced_include.arguments.end;
+                    result.push(forced_include);
+                }
+                None => i += 1,
+            }
+        }
+        Ok(result)
+    }
+
+    /// Macros defined by `-D` (`Some(value)`, `1` if omitted) and undefined by `-U` (`None`).
+    /// The last flag of each name wins, and names are in order of first appearance.
+    pub fn defines(&self) -> Result<Vec<(String, Option<String>)>> {
+        let args = self.arguments()?;
+        let mut defines: Vec<(String, Option<String>)> = Vec::new();
+        let mut i = 0;
+        while i < args.len() {
+            let arg = &args[i];
+            let (flag, value) = match arg.as_str() {
+                "-D" | "-U" => {
+                    i += 1;
+                    match args.get(i) {
+                        Some(value) => (&arg[..2], value.as_str()),
+                        None => break,
+                    }
+                }
+                _ if arg.starts_with("-D") || arg.starts_with("-U") => (&arg[..2], &arg[2..]),
+                _ => {
+                    i += 1;
+                    continue;
+                }
+            };
+            i += 1;
+            let (name, value) = match (flag, value.split_once('=')) {
+                ("-D", Some((name, value))) => (name, Some(value.to_string())),
+                ("-D", None) => (value, Some(String::from("1"))),
+                _ => (value, None),
+            };
+            match defines.iter_mut().find(|(v, _)| v == name) {
+                Some(define) => define.1 = value,
+                None => defines.push((name.to_string(), value)),
+            }
+        }
+        Ok(defines)
+    }
+
+    /// Same command without `-include` and `-imacros`
+    pub fn without_forced_includes(&self) -> Result<Self> {
+        let forced_includes = self.forced_includes()?;
+        let arguments: Vec<String> = self
+            .arguments()?
+            .into_iter()
+            .enumerate()
+            .filter(|(i, _)| !forced_includes.iter().any(|v| v.arguments.contains(i)))
+            .map(|(_, v)| v)
+            .collect();
+        // Keep the form of the entry
+        Ok(if self.arguments.is_some() {
+            CompileCommand {
+                arguments: Some(arguments),
+                ..self.clone()
+            }
+        } else {
+            CompileCommand {
+                command: Some(shell_words::join(arguments)),
+                ..self.clone()
+            }
+        })
+    }
+
+    pub fn file_type(&self, language_map: &LanguageMap) -> Option<FileType> {
+        language_map.classify(&self.file, self.arguments().ok().as_deref())
+    }
+
+    /// Arguments with `action` (e.g. `-E`) instead of `-c` and without `-o`
+    fn arguments_with_action(&self, action: &str) -> Result<Vec<String>> {
+        let mut args = self.arguments()?;
+        if args.is_empty() {
+            return Err(Error::CommandFormatError);
+        }
+
+        #[derive(Debug)]
+        struct ReplaceTargetOption {
+            c: Option<usize>,
+            o: Option<usize>,
+        }
+        let replace_target_option = ReplaceTargetOption {
+            c: args.iter().position(|v| v == "-c"),
+            o: args.iter().position(|v| v == "-o"),
+        };
+        trace!(
+            "preprocessor: replace_target_option={:?}",
+            replace_target_option
+        );
+        if let Some(c) = replace_target_option.c {
+            args[c] = action.to_string();
+        }
+        if let Some(o) = replace_target_option.o {
+            if o + 1 == args.len() {
+                return Err(Error::CommandFormatError);
+            }
+            args.drain(o..o + 2);
+        }
+        if replace_target_option.c.is_none() {
+            args.push(action.to_string());
+        }
+        Ok(args)
+    }
+
+    /// Run with `-E` instead of `-c` and returns the preprocessed source
+    pub fn run_preprocessor(&self, extra_args: &[&str]) -> Result<String> {
+        let mut args = self.arguments_with_action("-E")?;
+        args.extend(extra_args.iter().map(|v| v.to_string()));
+        trace!("preprocessor: args={:?}", args);
+
+        let output = Command::new(&args[0])
+            .args(&args[1..])
+            .current_dir(&self.directory)
+            .output()?;
+        if !output.stderr.is_empty() {
+            let mut stderr = io::stderr().lock();
+            stderr.write_all(&output.stderr)?;
+        }
+        exit_ok(output.status)?;
+
+        Ok(String::from_utf8(output.stdout)?)
+    }
+
+    /// Preprocessed source of `file`, keeping include statements of system headers.
+    /// `protected_macros` are left unexpanded: a header redefining each of them as itself is
+    /// forced after the other `-include` headers, so that they are still defined by the forced
+    /// headers kept by `-include` or restored as `#include` directives.
+    pub fn preprocessed(
+        &self,
+        protected_macros: &[String],
+        mode: ForcedIncludeMode,
+    ) -> Result<(String, PreprocessStats)> {
+        let (imacros, includes): (Vec<ForcedInclude>, Vec<ForcedInclude>) =
+            self.forced_includes()?.into_iter().partition(|v| v.imacros);
+        let forced_includes = ForcedIncludes {
+            mode,
+            includes: includes.into_iter().map(|v| v.path).collect(),
+            imacros: imacros.into_iter().map(|v| v.path).collect(),
+            directory: self.directory.clone(),
+        };
+        let mut extra_args = vec![
+            String::from("-dI"), // Preserve include statements
+            String::from("-C"),  // Preserve comments
+        ];
+        let protector = if protected_macros.is_empty() {
+            None
+        } else {
+            let path = env::temp_dir().join(format!(
+                "special-patch-protect-{}-{}.h",
+                std::process::id(),
+                TEMPORARY_HEADERS.fetch_add(1, Ordering::Relaxed)
+            ));
+            let content: String = protected_macros
+                .iter()
+                .map(|v| format!("#undef {0}\n#define {0} {0}\n", v))
+                .collect();
+            fs::write(&path, content)?;
+            extra_args.push(String::from("-include"));
+            extra_args.push(path.to_string_lossy().to_string());
+            Some(path)
+        };
+        let extra_args: Vec<&str> = extra_args.iter().map(|v| v.as_str()).collect();
+        let result = self.run_preprocessor(&extra_args);
+        if let Some(ref path) = protector {
+            fs::remove_file(path)?;
+        }
+        let mut patched = result?;
+        if let Some(ref path) = protector {
+            let quoted = format!("\"{}\"", path.display());
+            patched = patched
+                .lines()
+                // Directive printed by Clang
+                .filter(|v| !(v.starts_with("#include") && v.contains(&quoted)))
+                .map(|v| v.replace(&quoted, &format!("\"{}\"", PROTECTED_MACROS_FILE)) + "\n")
+                .collect();
+        }
+        Ok(Preprocessor::preprocess_with_stats(
+            &patched,
+            &forced_includes,
+        )?)
+    }
+
+    /// Replace `file` with its preprocessed source
+    pub fn preprocess(&self) -> Result<PreprocessStats> {
+        let (patched, stats) = self.preprocessed(&[], ForcedIncludeMode::default())?;
+        let mut patched_file = File::create(&self.file)?;
+        patched_file.write_all(patched.as_bytes())?;
+
+        Ok(stats)
+    }
+
+    /// Run with `-fsyntax-only` instead of `-c`.
+    /// Returns diagnostics if the file does not compile.
+    pub fn check_syntax(&self) -> Result<Option<String>> {
+        let args = self.arguments_with_action("-fsyntax-only")?;
+        trace!("check_syntax: args={:?}", args);
+        let output = Command::new(&args[0])
+            .args(&args[1..])
+            .current_dir(&self.directory)
+            .output()?;
+        if output.status.success() {
+            return Ok(None);
+        }
+        Ok(Some(String::from_utf8_lossy(&output.stderr).to_string()))
+    }
+
+    /// Run with the output replaced with `output`
+    pub fn compile_object(&self, output: &Path) -> Result<()> {
+        let mut args = self.arguments_with_action("-c")?;
+        args.push(String::from("-o"));
+        args.push(output.to_string_lossy().to_string());
+        trace!("compile_object: args={:?}", args);
+        let output = Command::new(&args[0])
+            .args(&args[1..])
+            .current_dir(&self.directory)
+            .output()?;
+        if !output.stderr.is_empty() {
+            let mut stderr = io::stderr().lock();
+            stderr.write_all(&output.stderr)?;
+        }
+        exit_ok(output.status)?;
+        Ok(())
+    }
+
+    /// Include graph of the translation unit. Relative paths are resolved against `directory`.
+    pub fn include_graph(&self) -> Result<IncludeGraph> {
+        let preprocessed = self.run_preprocessor(&[])?;
+        let graph = IncludeGraph::parse(&preprocessed)?;
+        Ok(graph.map_paths(|v| {
+            let path = self.directory.join(v);
+            path.canonicalize().unwrap_or(path)
+        }))
+    }
+
+    /// Output file given by `-o`, or `<file stem>.o` in `directory` as `-c` does
+    pub fn output(&self) -> Option<PathBuf> {
+        let args = self.arguments().ok()?;
+        match args.iter().position(|v| v == "-o") {
+            Some(o) => Some(self.directory.join(args.get(o + 1)?)),
+            None => Some(
+                self.directory
+                    .join(self.file.file_stem()?)
+                    .with_extension("o"),
+            ),
+        }
+    }
+
+    /// Project (i.e. non-system) headers that the translation unit includes
+    pub fn included_headers(&self) -> Result<Vec<PathBuf>> {
+        let preprocessed = self.run_preprocessor(&[])?;
+        let headers = Preprocessor::project_headers(&preprocessed)?;
+        Ok(headers
+            .into_iter()
+            .filter_map(|v| self.directory.join(v).canonicalize().ok())
+            .filter(|v| v != &self.file)
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(cmd: &str) -> CompileCommand {
+        CompileCommand {
+            directory: PathBuf::from("/src"),
+            command: Some(String::from(cmd)),
+            arguments: None,
+            file: PathBuf::from("/src/png.c"),
+        }
+    }
+
+    #[test]
+    fn compile_command_should_resolve_relative_paths() {
+        let command = CompileCommand {
+            directory: PathBuf::from("test/preprocessor"),
+            command: Some(String::from("cc -c main.c")),
+            arguments: None,
+            file: PathBuf::from("main.c"),
+        };
+        let command = command
+            .resolve(Path::new(env!("CARGO_MANIFEST_DIR")))
+            .unwrap();
+        assert!(command.directory.is_absolute());
+        assert_eq!(
+            command.file,
+            Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("test/preprocessor/main.c")
+                .canonicalize()
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn forced_includes() {
+        let command = command(
+            "cc -include canary.h -imacros config.h --include=a.h -includeb.h -include-pch c.pch -c png.c",
+        );
+        let forced_includes: Vec<(PathBuf, bool)> = command
+            .forced_includes()
+            .unwrap()
+            .into_iter()
+            .map(|v| (v.path, v.imacros))
+            .collect();
+        assert_eq!(
+            forced_includes,
+            vec![
+                (PathBuf::from("canary.h"), false),
+                (PathBuf::from("config.h"), true),
+                (PathBuf::from("a.h"), false),
+                (PathBuf::from("b.h"), false),
+            ]
+        );
+        assert_eq!(
+            command.without_forced_includes().unwrap().command.unwrap(),
+            "cc -include-pch c.pch -c png.c"
+        );
+    }
+
+    #[test]
+    fn defines() {
+        let command =
+            command(r#"cc -DNDEBUG -D VERSION=\"1.6\" -DFOO=1 -UBAR -UFOO -DEMPTY= -c png.c"#);
+        assert_eq!(
+            command.defines().unwrap(),
+            vec![
+                (String::from("NDEBUG"), Some(String::from("1"))),
+                (String::from("VERSION"), Some(String::from("\"1.6\""))),
+                (String::from("FOO"), None),
+                (String::from("BAR"), None),
+                (String::from("EMPTY"), Some(String::new())),
+            ]
+        );
+    }
+
+    #[test]
+    fn output() {
+        let output = |cmd: &str| {
+            CompileCommand {
+                file: PathBuf::from("/src/lib/png.c"),
+                ..command(cmd)
+            }
+            .output()
+        };
+        assert_eq!(
+            output("cc -c lib/png.c -o out/png.o"),
+            Some(PathBuf::from("/src/out/png.o"))
+        );
+        assert_eq!(output("cc -c lib/png.c"), Some(PathBuf::from("/src/png.o")));
+    }
+
+    #[test]
+    fn arguments_with_action() {
+        assert_eq!(
+            command("cc -c png.c -o png.o -O2")
+                .arguments_with_action("-E")
+                .unwrap(),
+            ["cc", "-E", "png.c", "-O2"]
+        );
+        assert_eq!(
+            command("cc png.c").arguments_with_action("-E").unwrap(),
+            ["cc", "png.c", "-E"]
+        );
+        assert!(matches!(
+            command("cc -c png.c -o").arguments_with_action("-E"),
+            Err(Error::CommandFormatError)
+        ));
+        assert!(matches!(
+            command("").arguments_with_action("-E"),
+            Err(Error::CommandFormatError)
+        ));
+    }
+}
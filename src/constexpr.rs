@@ -0,0 +1,315 @@
+//! Removal of `constexpr`, `consteval` and `constinit` specifiers.
+
+use crate::edit::Edit;
+use crate::tokenizer::{self, Token, TokenKind};
+
+use clap::ValueEnum;
+
+/// How to rewrite `constexpr` variables
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ConstexprVariables {
+    /// Replace with `const` (`inline const` for static data members, requires C++17)
+    Const,
+    /// Leave as is
+    Keep,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Scope {
+    Namespace,
+    Class,
+    Block,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Declaration {
+    Function,
+    Variable,
+}
+
+/// Remove `constexpr` and `consteval` from functions and lambdas, `constinit` from variables and
+/// rewrite `constexpr` variables as specified by `variables`. `if constexpr` and `if consteval`
+/// are kept. Functions which are not implicitly inline get `inline` instead, to keep them
+/// defined in headers without ODR violations.
+pub fn constexpr_edits(source: &str, variables: ConstexprVariables) -> Vec<Edit> {
+    let tokens: Vec<Token> = tokenizer::significant_tokens(source)
+        .into_iter()
+        .filter(|v| v.kind != TokenKind::Directive)
+        .collect();
+
+    let mut edits: Vec<Edit> = Vec::new();
+    let mut push = |token: &Token, replacement: &str| {
+        let mut range = token.start..token.end();
+        if replacement.is_empty() {
+            // Remove spaces around the removed specifier
+            let rest = &source[range.end..];
+            range.end += rest.len() - rest.trim_start_matches([' ', '\t']).len();
+            if source[range.end..].starts_with(['\r', '\n']) {
+                let previous_end = edits.last().map_or(0, |v: &Edit| v.range.end);
+                range.start = source[..range.start]
+                    .trim_end_matches([' ', '\t'])
+                    .len()
+                    .max(previous_end);
+            }
+        }
+        edits.push(Edit::new(range, replacement));
+    };
+    let mut scopes = vec![Scope::Namespace];
+    let mut statement_start = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        match token.text {
+            "{" => {
+                scopes.push(scope_kind(&tokens[statement_start..i]));
+                statement_start = i + 1;
+                continue;
+            }
+            "}" => {
+                if scopes.len() > 1 {
+                    scopes.pop();
+                }
+                statement_start = i + 1;
+                continue;
+            }
+            ";" => {
+                statement_start = i + 1;
+                continue;
+            }
+            "constexpr" | "consteval" | "constinit" => (),
+            _ => continue,
+        }
+        let previous = i.checked_sub(1).map(|v| &tokens[v]);
+        // `if constexpr`, `if consteval` and `if !consteval`
+        if previous.is_some_and(|v| v.is("if"))
+            || (previous.is_some_and(|v| v.is("!")) && i >= 2 && tokens[i - 2].is("if"))
+        {
+            continue;
+        }
+        if token.is("constinit") || is_lambda_specifier(&tokens, i) {
+            push(token, "");
+            continue;
+        }
+        let head = &tokens[statement_start..i];
+        let (declaration, declarator) = classify(&tokens, i);
+        let has = |keyword: &str| head.iter().chain(declarator.iter()).any(|v| v.is(keyword));
+        let in_class = scopes.last() == Some(&Scope::Class);
+        let replacement = match declaration {
+            // Member functions defined in classes are implicitly inline
+            Declaration::Function if in_class || has("inline") => "",
+            Declaration::Function => "inline",
+            Declaration::Variable if variables == ConstexprVariables::Keep => continue,
+            Declaration::Variable => {
+                // Static data members are implicitly inline
+                let inline = in_class && has("static") && !has("inline");
+                let declarator = top_level(declarator);
+                // `const` has to qualify the pointer itself to keep internal linkage
+                let pointer = declarator.iter().rposition(|v| v.is("*"));
+                let is_const = match pointer {
+                    Some(pointer) => declarator[pointer + 1..].iter().any(|v| v.is("const")),
+                    None => top_level(head)
+                        .iter()
+                        .chain(declarator.iter())
+                        .any(|v| v.is("const")),
+                };
+                let replacement = match (inline, is_const || pointer.is_some()) {
+                    (true, true) => "inline",
+                    (true, false) => "inline const",
+                    (false, true) => "",
+                    (false, false) => "const",
+                };
+                push(token, replacement);
+                if let Some(star) = pointer.filter(|_| !is_const).map(|v| declarator[v]) {
+                    let spaced = source[star.end()..].starts_with(char::is_whitespace);
+                    push(&star, if spaced { "* const" } else { "* const " });
+                }
+                continue;
+            }
+        };
+        push(token, replacement);
+    }
+    edits
+}
+
+/// Tokens outside brackets and template arguments
+fn top_level<'a>(tokens: &[Token<'a>]) -> Vec<Token<'a>> {
+    let mut depth: usize = 0;
+    let mut result = Vec::new();
+    for token in tokens.iter() {
+        match token.text {
+            "(" | "[" | "<" => depth += 1,
+            ")" | "]" | ">" => depth = depth.saturating_sub(1),
+            _ if depth == 0 => result.push(*token),
+            _ => (),
+        }
+    }
+    result
+}
+
+/// Kind of scope opened by `{` following `head`
+fn scope_kind(head: &[Token]) -> Scope {
+    let has = |keyword: &str| head.iter().any(|v| v.is(keyword));
+    if has("namespace") || (has("extern") && head.iter().any(|v| v.kind == TokenKind::String)) {
+        Scope::Namespace
+    } else if (has("class") || has("struct") || has("union"))
+        && !has("enum")
+        && !has("=")
+        && !has("(")
+    {
+        Scope::Class
+    } else {
+        Scope::Block
+    }
+}
+
+/// Whether the specifier at `index` follows parameters of a lambda expression
+/// (e.g. `[](int a) constexpr {}`)
+fn is_lambda_specifier(tokens: &[Token], index: usize) -> bool {
+    let previous = match index.checked_sub(1) {
+        Some(v) => &tokens[v],
+        None => return false,
+    };
+    match previous.text {
+        "mutable" => true,
+        // `[] constexpr {}`, but not `[[nodiscard]] constexpr int f()`
+        "]" => index < 2 || !tokens[index - 2].is("]"),
+        ")" => {
+            let mut depth = 0;
+            for j in (0..index).rev() {
+                if tokens[j].is(")") {
+                    depth += 1;
+                } else if tokens[j].is("(") {
+                    depth -= 1;
+                    if depth == 0 {
+                        return j > 0 && (tokens[j - 1].is("]") || tokens[j - 1].is(">"));
+                    }
+                }
+            }
+            false
+        }
+        _ => false,
+    }
+}
+
+/// Classify the declaration with the specifier at `index`. Returns tokens between the specifier
+/// and the initializer or parameters as well.
+fn classify<'a, 'b>(tokens: &'b [Token<'a>], index: usize) -> (Declaration, &'b [Token<'a>]) {
+    const FUNCTION_FOLLOWERS: [&str; 14] = [
+        "{", "->", "const", "volatile", "noexcept", "override", "final", "&", "&&", "requires",
+        "=", ":", "[", "try",
+    ];
+    let mut depth = 0;
+    let mut angle_depth = 0;
+    for (i, token) in tokens.iter().enumerate().skip(index + 1) {
+        let declarator = &tokens[index + 1..i];
+        match token.text {
+            "operator" if depth == 0 => return (Declaration::Function, declarator),
+            "<" => angle_depth += 1,
+            ">" if angle_depth > 0 => angle_depth -= 1,
+            "[" => depth += 1,
+            "]" => depth -= 1,
+            "(" if depth == 0 && angle_depth == 0 => {
+                let close = match tokenizer::matching_bracket(tokens, i) {
+                    Some(v) => v,
+                    None => return (Declaration::Variable, declarator),
+                };
+                let follower = tokens.get(close + 1).map_or("", |v| v.text);
+                // Direct initialization such as `constexpr Foo foo(1);`
+                let literal = tokens.get(i + 1).is_some_and(|v| {
+                    matches!(
+                        v.kind,
+                        TokenKind::Number | TokenKind::String | TokenKind::Char
+                    )
+                });
+                let is_function =
+                    FUNCTION_FOLLOWERS.contains(&follower) || (follower == ";" && !literal);
+                let declaration = if is_function {
+                    Declaration::Function
+                } else {
+                    Declaration::Variable
+                };
+                return (declaration, declarator);
+            }
+            "(" => depth += 1,
+            ")" => depth -= 1,
+            "=" | ";" | "{" if depth == 0 && angle_depth == 0 => {
+                return (Declaration::Variable, declarator)
+            }
+            _ => (),
+        }
+    }
+    (Declaration::Variable, &tokens[index + 1..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn remove(source: &str) -> String {
+        crate::edit::apply(source, &constexpr_edits(source, ConstexprVariables::Const))
+    }
+
+    #[test]
+    fn functions() {
+        assert_eq!(
+            remove("constexpr int f(int a) { return a; }"),
+            "inline int f(int a) { return a; }"
+        );
+        assert_eq!(
+            remove("[[nodiscard]] inline constexpr\nint\nf(int a,\n  int b);"),
+            "[[nodiscard]] inline\nint\nf(int a,\n  int b);"
+        );
+        assert_eq!(
+            remove("template <typename T>\nconstexpr T max(T a, T b) noexcept { return a; }"),
+            "template <typename T>\ninline T max(T a, T b) noexcept { return a; }"
+        );
+        assert_eq!(
+            remove("consteval int sq(int n) { return n * n; }"),
+            "inline int sq(int n) { return n * n; }"
+        );
+        assert_eq!(
+            remove("struct A {\n  constexpr A() : v(0) {}\n  constexpr bool operator<(const A &o) const { return v < o.v; }\n  int v;\n};"),
+            "struct A {\n  A() : v(0) {}\n  bool operator<(const A &o) const { return v < o.v; }\n  int v;\n};"
+        );
+        assert_eq!(
+            remove("auto f = [](int a) constexpr { return a; };"),
+            "auto f = [](int a) { return a; };"
+        );
+    }
+
+    #[test]
+    fn if_constexpr() {
+        let source = "void f() { if constexpr (N > 0) {} if consteval {} if !consteval {} }";
+        assert!(constexpr_edits(source, ConstexprVariables::Const).is_empty());
+    }
+
+    #[test]
+    fn variables() {
+        assert_eq!(
+            remove("constexpr int N = 3;\nconstexpr const char *name = \"a\";"),
+            "const int N = 3;\nconst char * const name = \"a\";"
+        );
+        assert_eq!(
+            remove("constexpr char *p = nullptr;\nconstexpr const char *const q = \"b\";"),
+            "char * const p = nullptr;\nconst char *const q = \"b\";"
+        );
+        assert_eq!(
+            remove("inline constexpr std::array<int, 2> a{1, 2};"),
+            "inline const std::array<int, 2> a{1, 2};"
+        );
+        assert_eq!(
+            remove("class A {\n  static constexpr int N = 3;\n  static constexpr const char *s = \"\";\n};"),
+            "class A {\n  static inline const int N = 3;\n  static inline const char * const s = \"\";\n};"
+        );
+        assert_eq!(
+            remove("void f() { static constexpr int N = 3; }"),
+            "void f() { static const int N = 3; }"
+        );
+        assert_eq!(remove("constinit int n = 0;"), "int n = 0;");
+        assert!(constexpr_edits("constexpr int N = 3;", ConstexprVariables::Keep).is_empty());
+    }
+
+    #[test]
+    fn ignore_comments_and_strings() {
+        let source = "// constexpr int f()\nconst char *s = \"constexpr\";\n#define CE constexpr\n";
+        assert!(constexpr_edits(source, ConstexprVariables::Const).is_empty());
+    }
+}
@@ -0,0 +1,42 @@
+use std::ops::Range;
+
+/// Replacement of a byte range of a file made by a rule.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edit {
+    pub range: Range<usize>,
+    pub replacement: String,
+}
+
+impl Edit {
+    pub fn new(range: Range<usize>, replacement: &str) -> Self {
+        Edit {
+            range,
+            replacement: replacement.to_string(),
+        }
+    }
+}
+
+/// Apply `edits`, which are sorted and do not overlap each other.
+pub fn apply(source: &str, edits: &[Edit]) -> String {
+    let mut result = String::with_capacity(source.len());
+    let mut offset = 0;
+    for edit in edits {
+        result.push_str(&source[offset..edit.range.start]);
+        result.push_str(&edit.replacement);
+        offset = edit.range.end;
+    }
+    result.push_str(&source[offset..]);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apply() {
+        let edits = vec![Edit::new(0..4, "(NULL)"), Edit::new(9..9, " ")];
+        assert_eq!(super::apply("NULL; int;", &edits), "(NULL); int ;");
+        assert_eq!(super::apply("NULL;", &[]), "NULL;");
+    }
+}
@@ -0,0 +1,82 @@
+use crate::preprocessor;
+
+use std::io;
+use std::process::ExitStatus;
+use std::string::FromUtf8Error;
+
+#[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum Error {
+    IoError(io::Error),
+    Utf8Error(FromUtf8Error),
+    /// Command exited with the status other than success
+    ExitStatusError(ExitStatus),
+    ShellWordsParseError(shell_words::ParseError),
+    RegexError(regex::Error),
+    IgnoreError(ignore::Error),
+    ObjectError(object::Error),
+    JsonError(serde_json::Error),
+    YamlError(serde_yaml::Error),
+    PreprocessorError(preprocessor::error::Error),
+    CommandFormatError,
+    /// Invalid value of an option (e.g. `--lang-map`)
+    OptionError(String),
+    /// Entries of `--canary-spec` which do not match the files
+    CanarySpecError(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::IoError(error)
+    }
+}
+
+impl From<FromUtf8Error> for Error {
+    fn from(error: FromUtf8Error) -> Self {
+        Error::Utf8Error(error)
+    }
+}
+
+impl From<shell_words::ParseError> for Error {
+    fn from(error: shell_words::ParseError) -> Self {
+        Error::ShellWordsParseError(error)
+    }
+}
+
+impl From<regex::Error> for Error {
+    fn from(error: regex::Error) -> Self {
+        Error::RegexError(error)
+    }
+}
+
+impl From<ignore::Error> for Error {
+    fn from(error: ignore::Error) -> Self {
+        Error::IgnoreError(error)
+    }
+}
+
+impl From<object::Error> for Error {
+    fn from(error: object::Error) -> Self {
+        Error::ObjectError(error)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(error: serde_json::Error) -> Self {
+        Error::JsonError(error)
+    }
+}
+
+impl From<serde_yaml::Error> for Error {
+    fn from(error: serde_yaml::Error) -> Self {
+        Error::YamlError(error)
+    }
+}
+
+impl From<preprocessor::error::Error> for Error {
+    fn from(error: preprocessor::error::Error) -> Self {
+        Error::PreprocessorError(error)
+    }
+}
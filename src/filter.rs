@@ -0,0 +1,157 @@
+use crate::Result;
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use regex::Regex;
+use std::fs;
+use std::path::Path;
+
+/// Prefix of a line in filter files which denotes a regex instead of a glob
+const REGEX_PREFIX: &str = "re:";
+
+/// Set of gitignore-style globs and regexes.
+#[derive(Debug)]
+struct Patterns {
+    globs: Gitignore,
+    regexes: Vec<Regex>,
+}
+
+impl Patterns {
+    fn new(globs: &[String], regexes: &[String], files: &[impl AsRef<Path>]) -> Result<Self> {
+        let mut builder = GitignoreBuilder::new("");
+        let mut regex_list = Vec::new();
+        for glob in globs.iter() {
+            builder.add_line(None, glob)?;
+        }
+        for regex in regexes.iter() {
+            regex_list.push(Regex::new(regex)?);
+        }
+        for file in files.iter() {
+            let file = file.as_ref();
+            for line in fs::read_to_string(file)?.lines() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                if let Some(regex) = line.strip_prefix(REGEX_PREFIX) {
+                    regex_list.push(Regex::new(regex.trim())?);
+                } else {
+                    builder.add_line(Some(file.to_path_buf()), line)?;
+                }
+            }
+        }
+        Ok(Patterns {
+            globs: builder.build()?,
+            regexes: regex_list,
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.globs.is_empty() && self.regexes.is_empty()
+    }
+
+    fn is_match(&self, path: &Path) -> bool {
+        // Same as `Gitignore::matched_path_or_any_parents()`, but also accepts paths out of root
+        for (i, ancestor) in path.ancestors().enumerate() {
+            if ancestor.as_os_str().is_empty() || ancestor.parent().is_none() {
+                break;
+            }
+            match self.globs.matched(ancestor, i > 0) {
+                Match::None => (),
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => break,
+            }
+        }
+        let path = path.to_string_lossy();
+        self.regexes.iter().any(|v| v.is_match(&path))
+    }
+}
+
+/// Selects files to process by `--only` and `--ignore` families of options.
+///
+/// Paths are matched relative to `root` (`directory` of compile_commands.json entries,
+/// or current directory for files given by arguments), or as is if they are out of `root`.
+#[derive(Debug)]
+pub struct Filter {
+    only: Patterns,
+    ignore: Patterns,
+}
+
+impl Filter {
+    pub fn new(
+        only: Vec<String>,
+        only_regex: Vec<String>,
+        only_from: Vec<impl AsRef<Path>>,
+        ignore: Vec<String>,
+        ignore_regex: Vec<String>,
+        ignore_from: Vec<impl AsRef<Path>>,
+    ) -> Result<Self> {
+        Ok(Filter {
+            only: Patterns::new(&only, &only_regex, &only_from)?,
+            ignore: Patterns::new(&ignore, &ignore_regex, &ignore_from)?,
+        })
+    }
+
+    pub fn is_selected(&self, path: &Path, root: &Path) -> bool {
+        let path = path.strip_prefix(root).unwrap_or(path);
+        (self.only.is_empty() || self.only.is_match(path)) && !self.ignore.is_match(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(only: &[&str], ignore: &[&str], ignore_regex: &[&str]) -> Filter {
+        let to_vec = |v: &[&str]| v.iter().map(|v| v.to_string()).collect::<Vec<String>>();
+        Filter::new(
+            to_vec(only),
+            vec![],
+            Vec::<&Path>::new(),
+            to_vec(ignore),
+            to_vec(ignore_regex),
+            Vec::<&Path>::new(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn ignore() {
+        let root = Path::new("/src/repo");
+        let filter = filter(&[], &["test/", "fuzz", "*_gen.c"], &[]);
+        assert!(!filter.is_selected(Path::new("/src/repo/test/main.c"), root));
+        assert!(!filter.is_selected(Path::new("/src/repo/lib/fuzz/main.c"), root));
+        assert!(!filter.is_selected(Path::new("/src/repo/lib/parser_gen.c"), root));
+        assert!(filter.is_selected(Path::new("/src/repo/lib/parser.c"), root));
+
+        // Out-of-tree files must not panic
+        assert!(!filter.is_selected(Path::new("/src/other/test/main.c"), root));
+        assert!(filter.is_selected(Path::new("/src/other/main.c"), root));
+    }
+
+    #[test]
+    fn ignore_anchored() {
+        let root = Path::new("/src/repo");
+        let filter = filter(&[], &["/lib/test"], &[]);
+        assert!(!filter.is_selected(Path::new("/src/repo/lib/test/main.c"), root));
+        assert!(filter.is_selected(Path::new("/src/repo/src/lib/test/main.c"), root));
+    }
+
+    #[test]
+    fn ignore_regex() {
+        let root = Path::new("/src/repo");
+        let filter = filter(&[], &[], &[r"_gen\.(c|h)$"]);
+        assert!(!filter.is_selected(Path::new("/src/repo/parser_gen.h"), root));
+        assert!(filter.is_selected(Path::new("/src/repo/parser.h"), root));
+    }
+
+    #[test]
+    fn only() {
+        let root = Path::new("/src/repo");
+        let filter = filter(&["lib/", "*.h"], &["lib/internal"], &[]);
+        assert!(filter.is_selected(Path::new("/src/repo/lib/png.c"), root));
+        assert!(filter.is_selected(Path::new("/src/repo/include/png.h"), root));
+        assert!(!filter.is_selected(Path::new("/src/repo/src/main.c"), root));
+        assert!(!filter.is_selected(Path::new("/src/repo/lib/internal/util.c"), root));
+    }
+}
@@ -0,0 +1,479 @@
+use crate::marker;
+use crate::preprocessor::included_header::IncludedHeader;
+use crate::preprocessor::path_like::PathLike;
+
+use clap::ValueEnum;
+use regex::Regex;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum IncludeStyle {
+    /// `#include <header.h>`
+    Angle,
+    /// `#include "header.h"`
+    Quote,
+}
+
+/// Where to insert code in a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Placement {
+    /// The very first line
+    Top,
+    /// After the leading comment block (e.g. license header)
+    AfterComments,
+    /// After `#pragma once` or `#ifndef X` / `#define X` include guard
+    AfterGuard,
+    /// After the last `#include` directive which is not in a conditional block
+    AfterIncludes,
+}
+
+/// Form of the block of macros given by the compile command, inserted by `--emit-defines`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DefinesStyle {
+    /// `-DNAME=VALUE` and `-UNAME` in a comment
+    Comment,
+    /// `#define NAME VALUE` directives for macros referenced by restored system headers, and a
+    /// comment for the others
+    Define,
+}
+
+/// Variables available in code snippets inserted by `--prelude` and `--epilogue`
+#[derive(Debug)]
+pub struct TemplateVariables<'a> {
+    /// `{{file}}`: absolute path of the file
+    pub file: &'a Path,
+    /// `{{relative_path}}`: path relative to the directory of the compile command
+    pub relative_path: &'a Path,
+    /// `{{index}}`: index of the file among all files to be patched
+    pub index: usize,
+}
+
+impl<'a> TemplateVariables<'a> {
+    pub fn render(&self, template: &str) -> String {
+        template
+            .replace("{{file}}", &self.file.to_string_lossy())
+            .replace("{{relative_path}}", &self.relative_path.to_string_lossy())
+            .replace("{{index}}", &self.index.to_string())
+    }
+}
+
+/// Include directive of `header_name`
+pub fn include_directive(header_name: &str, style: IncludeStyle) -> String {
+    match style {
+        IncludeStyle::Angle => format!("#include <{}>", header_name),
+        IncludeStyle::Quote => format!("#include \"{}\"", header_name),
+    }
+}
+
+/// Whether `source` already includes `header_name` in either `<...>` or `"..."` form
+pub fn includes_header(source: &str, header_name: &str) -> bool {
+    source.lines().any(|line| {
+        IncludedHeader::parse_line(line).is_ok_and(|v| v.path() == Path::new(header_name))
+    })
+}
+
+/// Insert include directives of `header_names` not yet included by `source`.
+/// Returns `None` if all of them are already included.
+pub fn insert_includes(
+    source: &str,
+    header_names: &[String],
+    style: IncludeStyle,
+    placement: Placement,
+) -> Option<String> {
+    let directives: Vec<String> = header_names
+        .iter()
+        .filter(|v| !includes_header(source, v))
+        .map(|v| include_directive(v, style))
+        .collect();
+    if directives.is_empty() {
+        return None;
+    }
+    Some(insert_at(source, placement, &directives.join("\n")))
+}
+
+/// Names of `defines` which appear in any of `headers`. Unreadable headers are skipped.
+pub fn referenced_macros(
+    defines: &[(String, Option<String>)],
+    headers: &HashSet<PathBuf>,
+) -> HashSet<String> {
+    let patterns: Vec<(&String, Regex)> = defines
+        .iter()
+        .filter_map(|(name, _)| {
+            Some((
+                name,
+                Regex::new(&format!(r"\b{}\b", regex::escape(name))).ok()?,
+            ))
+        })
+        .collect();
+    let mut referenced = HashSet::new();
+    for header in headers.iter() {
+        if let Ok(content) = fs::read_to_string(header) {
+            for (name, pattern) in patterns.iter() {
+                if !referenced.contains(*name) && pattern.is_match(&content) {
+                    referenced.insert(name.to_string());
+                }
+            }
+        }
+    }
+    referenced
+}
+
+/// Block of `defines` (see `CompileCommand::defines()`) in `style`.
+/// With `DefinesStyle::Define`, `-D` macros in `referenced` (see `referenced_macros()`) are
+/// defined unless already defined, so that rebuilding with the original command redefines
+/// nothing. The other flags, including all `-U`, are listed in a comment.
+/// Returns `None` if there are no macros.
+pub fn defines_block(
+    defines: &[(String, Option<String>)],
+    style: DefinesStyle,
+    referenced: &HashSet<String>,
+) -> Option<String> {
+    if defines.is_empty() {
+        return None;
+    }
+    let (defined, listed): (Vec<_>, Vec<_>) = defines.iter().partition(|(name, value)| {
+        style == DefinesStyle::Define && value.is_some() && referenced.contains(name)
+    });
+    let mut block = String::new();
+    if !listed.is_empty() {
+        block.push_str("/* Macros given by the compile command:\n");
+        for (name, value) in listed.iter() {
+            let flag = match value {
+                Some(value) => format!("-D{}={}", name, value),
+                None => format!("-U{}", name),
+            };
+            block.push_str(&format!(" *   {}\n", flag.replace("*/", "* /")));
+        }
+        block.push_str(" */\n");
+    }
+    if !defined.is_empty() {
+        block.push_str("/* Macros given by the compile command and used by system headers */\n");
+        for (name, value) in defined.iter() {
+            block.push_str(&format!(
+                "#ifndef {0}\n#define {0} {1}\n#endif\n",
+                name,
+                value.as_deref().unwrap_or_default()
+            ));
+        }
+    }
+    Some(block)
+}
+
+/// Insert code snippet `text` at `placement`, after a marker comment.
+/// Returns `None` if `source` already has a prelude.
+pub fn insert_prelude(source: &str, text: &str, placement: Placement) -> Option<String> {
+    if text.trim().is_empty() || marker::has_snippet(source, "prelude") {
+        return None;
+    }
+    let text = format!("{}\n{}", marker::snippet_marker("prelude"), text);
+    Some(insert_at(source, placement, &text))
+}
+
+/// Insert code snippet `text` at the end of `source`, but before `#endif` of include guard and
+/// the marker comment of rules, after a marker comment of its own. Returns `None` if `source`
+/// already has an epilogue.
+pub fn insert_epilogue(source: &str, text: &str) -> Option<String> {
+    if text.trim().is_empty() || marker::has_snippet(source, "epilogue") {
+        return None;
+    }
+    let text = format!("{}\n{}", marker::snippet_marker("epilogue"), text);
+    let mut offset = marker::marker_offset(source);
+    if let Some((_, true)) = include_guard(source) {
+        let conditional_end = Regex::new(r"^\s*#\s*endif\b").unwrap();
+        if let Some((end, _)) = lines(&source[..offset])
+            .filter(|(_, line)| conditional_end.is_match(line))
+            .last()
+        {
+            offset = end;
+        }
+    }
+    Some(insert_at_offset(source, offset, &text))
+}
+
+/// Insert `text` as whole lines at `placement`
+pub fn insert_at(source: &str, placement: Placement, text: &str) -> String {
+    insert_at_offset(source, insertion_offset(source, placement), text)
+}
+
+fn insert_at_offset(source: &str, offset: usize, text: &str) -> String {
+    let mut result = String::with_capacity(source.len() + text.len() + 2);
+    result.push_str(&source[..offset]);
+    if offset > 0 && !source[..offset].ends_with('\n') {
+        result.push('\n');
+    }
+    result.push_str(text);
+    if !text.ends_with('\n') {
+        result.push('\n');
+    }
+    result.push_str(&source[offset..]);
+    result
+}
+
+/// Byte offset of the beginning of the line where code is inserted at `placement`
+pub fn insertion_offset(source: &str, placement: Placement) -> usize {
+    match placement {
+        Placement::Top => 0,
+        Placement::AfterComments => after_comments(source),
+        Placement::AfterGuard => after_guard(source).unwrap_or_else(|| after_comments(source)),
+        Placement::AfterIncludes => after_includes(source)
+            .or_else(|| after_guard(source))
+            .unwrap_or_else(|| after_comments(source)),
+    }
+}
+
+/// Lines with their byte offsets
+fn lines(source: &str) -> impl Iterator<Item = (usize, &str)> {
+    source.split_inclusive('\n').scan(0, |offset, line| {
+        let start = *offset;
+        *offset += line.len();
+        Some((start, line))
+    })
+}
+
+fn after_comments(source: &str) -> usize {
+    let mut in_block_comment = false;
+    for (offset, line) in lines(source) {
+        let mut rest = line.trim();
+        if in_block_comment {
+            match rest.find("*/") {
+                Some(end) => {
+                    in_block_comment = false;
+                    rest = rest[end + 2..].trim();
+                }
+                None => continue,
+            }
+        }
+        if rest.is_empty() || rest.starts_with("//") {
+            continue;
+        }
+        if let Some(comment) = rest.strip_prefix("/*") {
+            match comment.find("*/") {
+                Some(end) if comment[end + 2..].trim().is_empty() => continue,
+                Some(_) => return offset,
+                None => {
+                    in_block_comment = true;
+                    continue;
+                }
+            }
+        }
+        return offset;
+    }
+    source.len()
+}
+
+/// Offset of the line after the include guard, if `source` has one
+fn after_guard(source: &str) -> Option<usize> {
+    include_guard(source).map(|(offset, _)| offset)
+}
+
+/// Offset of the line after the include guard, and whether the guard is a conditional
+/// (`#ifndef X` / `#define X`) rather than `#pragma once`
+fn include_guard(source: &str) -> Option<(usize, bool)> {
+    let pragma_once = Regex::new(r"^\s*#\s*pragma\s+once\b").unwrap();
+    let ifndef = Regex::new(r"^\s*#\s*(?:ifndef\s+(\w+)|if\s+!\s*defined\s*\(?\s*(\w+))").unwrap();
+    let define = Regex::new(r"^\s*#\s*define\s+(\w+)").unwrap();
+
+    let start = after_comments(source);
+    let mut guard: Option<&str> = None;
+    for (offset, line) in lines(&source[start..]) {
+        let offset = start + offset;
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with("//") {
+            continue;
+        }
+        if pragma_once.is_match(line) {
+            return Some((offset + line.len(), false));
+        }
+        match guard {
+            None => {
+                let caps = ifndef.captures(line)?;
+                guard = caps.get(1).or_else(|| caps.get(2)).map(|v| v.as_str());
+            }
+            Some(name) => {
+                return match define.captures(line) {
+                    Some(caps) if &caps[1] == name => Some((offset + line.len(), true)),
+                    _ => None,
+                };
+            }
+        }
+    }
+    None
+}
+
+/// Offset of the line after the last `#include` directive out of conditional blocks
+fn after_includes(source: &str) -> Option<usize> {
+    let conditional_begin = Regex::new(r"^\s*#\s*if").unwrap();
+    let conditional_end = Regex::new(r"^\s*#\s*endif\b").unwrap();
+
+    let guard = after_guard(source);
+    let mut depth = 0;
+    let mut base_depth = None;
+    let mut last_include = None;
+    for (offset, line) in lines(source) {
+        // Conditional of include guard itself is not counted
+        if guard.is_some_and(|v| offset >= v) && base_depth.is_none() {
+            base_depth = Some(depth);
+        }
+        if conditional_begin.is_match(line) {
+            depth += 1;
+        } else if conditional_end.is_match(line) {
+            depth -= 1;
+        } else if depth == base_depth.unwrap_or(0)
+            && IncludedHeader::parse_line(line.trim_start()).is_ok()
+        {
+            last_include = Some(offset + line.len());
+        }
+    }
+    last_include
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: &str = "/*
+ * License
+ */
+
+// Comment
+#ifndef _calc_h_
+#define _calc_h_
+
+#include <stdio.h>
+#ifdef DEBUG
+#include <debug.h>
+#endif
+
+int inc(int a);
+
+#endif
+";
+
+    #[test]
+    fn insertion_offset() {
+        let offset = |placement| {
+            let offset = super::insertion_offset(HEADER, placement);
+            HEADER[offset..].lines().next().unwrap()
+        };
+        assert_eq!(offset(Placement::Top), "/*");
+        assert_eq!(offset(Placement::AfterComments), "#ifndef _calc_h_");
+        assert_eq!(offset(Placement::AfterGuard), "");
+        assert_eq!(offset(Placement::AfterIncludes), "#ifdef DEBUG");
+    }
+
+    #[test]
+    fn insertion_offset_pragma_once() {
+        let source = "// License\n#pragma once\n#include \"a.h\"\n";
+        assert_eq!(
+            &source[super::insertion_offset(source, Placement::AfterGuard)..],
+            "#include \"a.h\"\n"
+        );
+        assert_eq!(
+            super::insertion_offset(source, Placement::AfterIncludes),
+            source.len()
+        );
+    }
+
+    #[test]
+    fn insertion_offset_without_guard() {
+        let source = "/* License */ int a;\nint b;";
+        assert_eq!(super::insertion_offset(source, Placement::AfterComments), 0);
+        assert_eq!(super::insertion_offset(source, Placement::AfterGuard), 0);
+        assert_eq!(super::insertion_offset(source, Placement::AfterIncludes), 0);
+    }
+
+    #[test]
+    fn insert_prelude() {
+        let patched =
+            super::insert_prelude(HEADER, "#define CANARY 1\n", Placement::AfterGuard).unwrap();
+        assert!(patched.contains(
+            "#define _calc_h_\n/* special-patch: inserted=prelude */\n#define CANARY 1\n\n#include <stdio.h>"
+        ));
+        // Snippets rendered differently (e.g. by another `{{index}}`) are not inserted again
+        assert_eq!(
+            super::insert_prelude(&patched, "#define CANARY 2\n", Placement::AfterGuard),
+            None
+        );
+    }
+
+    #[test]
+    fn insert_epilogue() {
+        let patched = super::insert_epilogue(HEADER, "void end(void);\n").unwrap();
+        assert!(patched.ends_with(
+            "int inc(int a);\n\n/* special-patch: inserted=epilogue */\nvoid end(void);\n#endif\n"
+        ));
+        assert_eq!(super::insert_epilogue(&patched, "void end(int);\n"), None);
+
+        let source = "int main() {}\n/* special-patch: applied=null */\n";
+        assert_eq!(
+            super::insert_epilogue(source, "void end(void);").unwrap(),
+            "int main() {}\n/* special-patch: inserted=epilogue */\nvoid end(void);\n/* special-patch: applied=null */\n"
+        );
+    }
+
+    #[test]
+    fn render() {
+        let variables = TemplateVariables {
+            file: Path::new("/src/repo/lib/png.c"),
+            relative_path: Path::new("lib/png.c"),
+            index: 3,
+        };
+        assert_eq!(
+            variables.render("/* {{file}} {{relative_path}} {{index}} */"),
+            "/* /src/repo/lib/png.c lib/png.c 3 */"
+        );
+    }
+
+    #[test]
+    fn defines_block() {
+        let defines = vec![
+            (String::from("NDEBUG"), Some(String::from("1"))),
+            (String::from("VERSION"), Some(String::from("\"1.6\""))),
+            (String::from("FOO"), None),
+        ];
+        let referenced = HashSet::from([String::from("NDEBUG"), String::from("FOO")]);
+        assert_eq!(
+            super::defines_block(&defines, DefinesStyle::Comment, &referenced).unwrap(),
+            "/* Macros given by the compile command:\n *   -DNDEBUG=1\n *   -DVERSION=\"1.6\"\n *   -UFOO\n */\n"
+        );
+        // Only referenced macros are defined, and `-U` is never emitted as `#undef`
+        assert_eq!(
+            super::defines_block(&defines, DefinesStyle::Define, &referenced).unwrap(),
+            "/* Macros given by the compile command:\n *   -DVERSION=\"1.6\"\n *   -UFOO\n */\n\
+            /* Macros given by the compile command and used by system headers */\n\
+            #ifndef NDEBUG\n#define NDEBUG 1\n#endif\n"
+        );
+        assert_eq!(
+            super::defines_block(&[], DefinesStyle::Define, &referenced),
+            None
+        );
+
+        let header =
+            std::env::temp_dir().join(format!("special-patch-defines-{}.h", std::process::id()));
+        fs::write(
+            &header,
+            "#ifdef NDEBUG\n#define assert(e) ((void)0)\n#endif\n",
+        )
+        .unwrap();
+        assert_eq!(
+            super::referenced_macros(&defines, &HashSet::from([header.clone()])),
+            HashSet::from([String::from("NDEBUG")])
+        );
+        fs::remove_file(header).unwrap();
+    }
+
+    #[test]
+    fn insert_includes() {
+        let headers = vec![String::from("stdio.h"), String::from("canary.h")];
+        let patched =
+            super::insert_includes(HEADER, &headers, IncludeStyle::Quote, Placement::AfterGuard)
+                .unwrap();
+        assert!(patched.contains("#define _calc_h_\n#include \"canary.h\"\n\n#include <stdio.h>"));
+        assert_eq!(
+            super::insert_includes(&patched, &headers, IncludeStyle::Angle, Placement::Top),
+            None
+        );
+    }
+}
@@ -0,0 +1,294 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Language {
+    C,
+    Cxx,
+    ObjC,
+    ObjCxx,
+    Cuda,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FileKind {
+    Source,
+    Header,
+}
+
+/// Language and kind of a file, named after values of `-x` option of gcc/clang (e.g. `c++-header`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FileType {
+    pub language: Language,
+    pub kind: FileKind,
+}
+
+impl FileType {
+    pub const fn new(language: Language, kind: FileKind) -> Self {
+        FileType { language, kind }
+    }
+
+    pub fn is_source(&self) -> bool {
+        self.kind == FileKind::Source
+    }
+
+    /// C++ and its dialects
+    pub fn is_cxx(&self) -> bool {
+        matches!(
+            self.language,
+            Language::Cxx | Language::ObjCxx | Language::Cuda
+        )
+    }
+
+    /// Classify a file by its extension as gcc does.
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        use FileKind::*;
+        use Language::*;
+        // Case-sensitive extensions first (e.g. `.C` is C++ while `.c` is C)
+        let file_type = match extension {
+            "C" => Some((Cxx, Source)),
+            "H" => Some((Cxx, Header)),
+            "M" => Some((ObjCxx, Source)),
+            _ => None,
+        };
+        let file_type = file_type.or_else(|| match extension.to_ascii_lowercase().as_str() {
+            "c" => Some((C, Source)),
+            "h" => Some((C, Header)),
+            "cc" | "cp" | "cpp" | "cxx" | "c++" => Some((Cxx, Source)),
+            "hh" | "hp" | "hpp" | "hxx" | "h++" | "inl" | "ipp" | "tcc" | "tpp" => {
+                Some((Cxx, Header))
+            }
+            "m" => Some((ObjC, Source)),
+            "mm" => Some((ObjCxx, Source)),
+            "cu" => Some((Cuda, Source)),
+            "cuh" => Some((Cuda, Header)),
+            _ => None,
+        });
+        file_type.map(|(language, kind)| FileType::new(language, kind))
+    }
+}
+
+impl FromStr for FileType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use FileKind::*;
+        use Language::*;
+        let (language, kind) = match s {
+            "c" | "cpp-output" => (C, Source),
+            "c-header" => (C, Header),
+            "c++" | "c++-cpp-output" => (Cxx, Source),
+            "c++-header" => (Cxx, Header),
+            "objective-c" | "objc-cpp-output" => (ObjC, Source),
+            "objective-c-header" => (ObjC, Header),
+            "objective-c++" | "objective-c++-cpp-output" => (ObjCxx, Source),
+            "objective-c++-header" => (ObjCxx, Header),
+            "cuda" => (Cuda, Source),
+            _ => return Err(format!("Unknown language: {}", s)),
+        };
+        Ok(FileType::new(language, kind))
+    }
+}
+
+impl fmt::Display for FileType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let language = match self.language {
+            Language::C => "c",
+            Language::Cxx => "c++",
+            Language::ObjC => "objective-c",
+            Language::ObjCxx => "objective-c++",
+            Language::Cuda => "cuda",
+        };
+        match self.kind {
+            FileKind::Source => write!(f, "{}", language),
+            FileKind::Header => write!(f, "{}-header", language),
+        }
+    }
+}
+
+/// Classifies files by `-x <lang>` in compile commands, user-defined extension map and extensions.
+#[derive(Debug, Default)]
+pub struct LanguageMap {
+    extensions: HashMap<String, FileType>,
+}
+
+impl LanguageMap {
+    /// Parse entries of `--lang-map` in form of `EXT=LANG` (e.g. `inc=c-header`).
+    pub fn new(entries: &[String]) -> Result<Self, String> {
+        let mut extensions = HashMap::new();
+        for entry in entries.iter() {
+            let (extension, file_type) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid language map (expected EXT=LANG): {}", entry))?;
+            extensions.insert(
+                extension.trim_start_matches('.').to_string(),
+                file_type.parse()?,
+            );
+        }
+        Ok(LanguageMap { extensions })
+    }
+
+    /// `arguments` is the arguments of the compile command for the file if exists.
+    pub fn classify(&self, path: &Path, arguments: Option<&[String]>) -> Option<FileType> {
+        if let Some(file_type) = arguments.and_then(Self::language_option) {
+            return Some(file_type);
+        }
+        let extension = path.extension().and_then(OsStr::to_str)?;
+        let file_type = self
+            .extensions
+            .get(extension)
+            .copied()
+            .or_else(|| FileType::from_extension(extension))?;
+        // C++ compiler drivers compile C files as C++
+        let compiler = arguments.and_then(|v| v.first());
+        if file_type.language == Language::C && compiler.is_some_and(|v| v.ends_with("++")) {
+            return Some(FileType::new(Language::Cxx, file_type.kind));
+        }
+        Some(file_type)
+    }
+
+    /// Headers with an ambiguous extension (e.g. `.h`) or without extension inherit the language
+    /// of the translation unit which includes them.
+    pub fn classify_header(&self, path: &Path, including: FileType) -> FileType {
+        let extension = path.extension().and_then(OsStr::to_str);
+        if let Some(file_type) = extension.and_then(|v| self.extensions.get(v)) {
+            return *file_type;
+        }
+        match extension.and_then(FileType::from_extension) {
+            Some(file_type) if file_type.language != Language::C => file_type,
+            _ => FileType::new(including.language, FileKind::Header),
+        }
+    }
+
+    /// Last `-x <lang>` or `-x<lang>` in arguments. `-x none` resets it.
+    fn language_option(arguments: &[String]) -> Option<FileType> {
+        let mut file_type = None;
+        let mut iter = arguments.iter();
+        while let Some(argument) = iter.next() {
+            let language = if argument == "-x" {
+                iter.next().map(String::as_str)
+            } else {
+                argument.strip_prefix("-x")
+            };
+            match language {
+                Some("none") => file_type = None,
+                Some(language) => file_type = language.parse().ok().or(file_type),
+                None => (),
+            }
+        }
+        file_type
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use FileKind::*;
+    use Language::*;
+
+    fn args(v: &[&str]) -> Vec<String> {
+        v.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn from_extension() {
+        assert_eq!(
+            FileType::from_extension("c"),
+            Some(FileType::new(C, Source))
+        );
+        assert_eq!(
+            FileType::from_extension("C"),
+            Some(FileType::new(Cxx, Source))
+        );
+        assert_eq!(
+            FileType::from_extension("CPP"),
+            Some(FileType::new(Cxx, Source))
+        );
+        assert_eq!(
+            FileType::from_extension("c++"),
+            Some(FileType::new(Cxx, Source))
+        );
+        assert_eq!(
+            FileType::from_extension("hxx"),
+            Some(FileType::new(Cxx, Header))
+        );
+        assert_eq!(
+            FileType::from_extension("inl"),
+            Some(FileType::new(Cxx, Header))
+        );
+        assert_eq!(
+            FileType::from_extension("mm"),
+            Some(FileType::new(ObjCxx, Source))
+        );
+        assert_eq!(
+            FileType::from_extension("cu"),
+            Some(FileType::new(Cuda, Source))
+        );
+        assert_eq!(FileType::from_extension("rs"), None);
+    }
+
+    #[test]
+    fn classify() {
+        let map = LanguageMap::new(&args(&["inc=c-header", ".h=c++-header"])).unwrap();
+        assert_eq!(
+            map.classify(Path::new("a.inc"), None),
+            Some(FileType::new(C, Header))
+        );
+        assert_eq!(
+            map.classify(Path::new("a.h"), None),
+            Some(FileType::new(Cxx, Header))
+        );
+        assert_eq!(
+            map.classify(
+                Path::new("a.c"),
+                Some(&args(&["cc", "-x", "c++", "-c", "a.c"]))
+            ),
+            Some(FileType::new(Cxx, Source))
+        );
+        assert_eq!(
+            map.classify(
+                Path::new("a.c"),
+                Some(&args(&["cc", "-xc++", "-x", "none", "a.c"]))
+            ),
+            Some(FileType::new(C, Source))
+        );
+        assert_eq!(
+            map.classify(Path::new("a.c"), Some(&args(&["g++", "-c", "a.c"]))),
+            Some(FileType::new(Cxx, Source))
+        );
+        assert_eq!(map.classify(Path::new("Makefile"), None), None);
+        assert!(LanguageMap::new(&args(&["inc=pascal"])).is_err());
+    }
+
+    #[test]
+    fn classify_header() {
+        let map = LanguageMap::default();
+        let cxx = FileType::new(Cxx, Source);
+        assert_eq!(
+            map.classify_header(Path::new("a.h"), cxx),
+            FileType::new(Cxx, Header)
+        );
+        assert_eq!(
+            map.classify_header(Path::new("vector"), cxx),
+            FileType::new(Cxx, Header)
+        );
+        assert_eq!(
+            map.classify_header(Path::new("a.h"), FileType::new(C, Source)),
+            FileType::new(C, Header)
+        );
+        assert_eq!(
+            map.classify_header(Path::new("a.cuh"), cxx),
+            FileType::new(Cuda, Header)
+        );
+    }
+
+    #[test]
+    fn display() {
+        for name in ["c", "c++-header", "objective-c++", "cuda"] {
+            assert_eq!(name.parse::<FileType>().unwrap().to_string(), name);
+        }
+    }
+}
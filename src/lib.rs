@@ -0,0 +1,40 @@
+//! Source code rewriting for compilation databases.
+//!
+//! - `CompileCommand::load()` loads compile_commands.json
+//! - `patch_buffer()` applies rules configured by `RuleConfig` to a single buffer
+//! - `run()` patches a tree as the `special-patch` command does, and returns a `Report`
+
+pub mod amalgamation;
+pub mod backup;
+pub mod bisect;
+pub mod cache;
+pub mod canary;
+pub mod compile_command;
+pub mod constexpr;
+pub mod edit;
+pub mod error;
+mod filter;
+pub mod injection;
+pub mod language;
+pub mod marker;
+pub mod null;
+pub mod objects;
+pub mod options;
+pub mod patch;
+pub mod preprocessor;
+pub mod probe;
+pub mod prune;
+pub mod report;
+pub mod rule;
+pub mod run;
+pub mod tokenizer;
+
+pub use amalgamation::Amalgamation;
+pub use bisect::BisectOutcome;
+pub use compile_command::CompileCommand;
+pub use error::{Error, Result};
+pub use options::{Options, Selection};
+pub use patch::{patch_buffer, PatchedBuffer, RuleConfig};
+pub use report::Report;
+pub use rule::Rule;
+pub use run::{amalgamate, bisect_file, include_graphs, inspect_file, list_files, run};
@@ -1,423 +1,541 @@
-#![feature(exit_status_error)]
-
-pub mod preprocessor;
-
-use preprocessor::Preprocessor;
-
-use clap::Parser;
+use special_patch::backup::{self, Backup};
+use special_patch::injection::DefinesStyle;
+use special_patch::options::{BackupOptions, Checks, RuleOptions};
+use special_patch::patch::RuleEdit;
+use special_patch::preprocessor::include_graph::IncludeGraph;
+use special_patch::preprocessor::path_like::PathLike;
+use special_patch::preprocessor::{AnnotatedLine, ForcedIncludeMode, ForcedIncludes, Preprocessor};
+use special_patch::{BisectOutcome, CompileCommand, Options, Report, Selection};
+
+use clap::{Parser, Subcommand, ValueEnum};
 #[allow(unused_imports)]
 use log::{error, info, trace, warn};
-#[allow(unused_imports)]
-use rayon::prelude::*;
-use regex::Captures;
-use regex::Regex;
-use serde::Deserialize;
-use std::collections::HashSet;
 use std::env;
-use std::ffi::OsStr;
 use std::fs;
-use std::fs::File;
-use std::io;
-use std::io::BufReader;
-use std::io::BufWriter;
-use std::io::Read;
-use std::io::Write;
-use std::path::PathBuf;
-use std::process::Command;
-use std::process::ExitStatusError;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
 struct Cli {
-    #[clap(long = "compile-commands", help = "Path to compile_commands.json")]
-    compile_commands: PathBuf,
-    files: Vec<PathBuf>,
-    #[clap(
-        long = "preprocessor",
-        help = "Replace original source code with preprocessed one"
-    )]
-    preprocessor: bool,
-    #[clap(long = "include", help = "Add include directive on the top of files")]
-    include: Option<String>,
-    #[clap(long = "ignore", help = "Ignore directory while process")]
-    ignore: Vec<PathBuf>,
+    #[clap(subcommand)]
+    command: Command,
 }
 
-#[derive(Deserialize, Debug)]
-#[allow(dead_code)]
-struct CompileCommand {
-    directory: PathBuf,
-    #[serde(default)]
-    command: Option<String>,
-    #[serde(default)]
-    arguments: Option<Vec<String>>,
-    file: PathBuf,
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    Text,
+    Json,
 }
 
-#[derive(Debug)]
-enum Error {
-    IoError(io::Error),
-    ExitStatusError(ExitStatusError),
-    ShellWordsParseError(shell_words::ParseError),
-    CommandFormatError,
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum DepsFormat {
+    Dot,
+    Json,
+    Make,
 }
 
-type Result<T> = std::result::Result<T, Error>;
-
-impl From<io::Error> for Error {
-    fn from(error: io::Error) -> Self {
-        Error::IoError(error)
+impl DepsFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            DepsFormat::Dot => "dot",
+            DepsFormat::Json => "json",
+            DepsFormat::Make => "d",
+        }
     }
 }
 
-impl From<ExitStatusError> for Error {
-    fn from(error: ExitStatusError) -> Self {
-        Error::ExitStatusError(error)
-    }
+#[derive(Subcommand)]
+enum Command {
+    #[clap(about = "Apply rules and insert code snippets to files")]
+    Patch {
+        #[clap(flatten)]
+        options: Options,
+        #[clap(
+            long = "report",
+            help = "Write rules applied to each file and preprocessing stats in JSON"
+        )]
+        report: Option<PathBuf>,
+    },
+    #[clap(about = "Replace translation units with preprocessed ones")]
+    Preprocess {
+        #[clap(flatten)]
+        selection: Selection,
+        #[clap(
+            long = "marker",
+            help = "Record preprocessing in a comment at the end of files. Recorded files are not preprocessed again"
+        )]
+        marker: bool,
+        #[clap(
+            long = "prune",
+            help = "Remove static functions, static variables, prototypes, typedefs and struct definitions unreferenced in preprocessed C sources"
+        )]
+        prune: bool,
+        #[clap(
+            long = "protect-macro",
+            help = "Keep invocations of the macro unexpanded by the preprocessor (e.g. MAGMA_LOG). The macro must be defined by a header forced by -include"
+        )]
+        protect_macro: Vec<String>,
+        #[clap(
+            long = "forced-includes",
+            help = "How to handle headers forced by -include: drop them keeping -include in compile commands (strip), restore one #include directive for compile commands without -include (directive), or inline them writing compile commands without -include and -imacros to --rewritten-compile-commands (inline)",
+            value_enum,
+            default_value = "strip"
+        )]
+        forced_includes: ForcedIncludeMode,
+        #[clap(
+            long = "rewritten-compile-commands",
+            help = "Where to write compile_commands.json without -include and -imacros of preprocessed files",
+            required_if_eq("forced-includes", "inline")
+        )]
+        rewritten_compile_commands: Option<PathBuf>,
+        #[clap(
+            long = "emit-defines",
+            help = "Insert a block listing -D and -U flags of the compile command at the top of preprocessed files, as a comment (comment), or as #define directives for macros referenced by restored system headers and a comment for the others (define)",
+            value_enum
+        )]
+        emit_defines: Option<DefinesStyle>,
+        #[clap(flatten)]
+        checks: Checks,
+        #[clap(flatten)]
+        backup: BackupOptions,
+        #[clap(long = "report", help = "Write preprocessing stats in JSON")]
+        report: Option<PathBuf>,
+    },
+    #[clap(about = "Restore files modified by `patch` and `preprocess` to the original")]
+    Restore {
+        #[clap(help = "Files to restore. All files kept in the backup directory by default")]
+        files: Vec<PathBuf>,
+        #[clap(
+            long = "backup-dir",
+            help = "Directory keeping original files",
+            default_value = backup::DEFAULT_BACKUP_DIR
+        )]
+        backup_dir: PathBuf,
+    },
+    #[clap(
+        about = "Show edits which `patch` would make in a file, or how `preprocess` handles each line of a preprocessed file"
+    )]
+    Inspect {
+        file: PathBuf,
+        #[clap(
+            long = "preprocessed",
+            help = "Treat the file as output of `-E -dI` (default for .E, .i and .ii files)"
+        )]
+        preprocessed: bool,
+        #[clap(long = "format", value_enum, default_value = "text")]
+        format: OutputFormat,
+        #[clap(
+            long = "lang-map",
+            help = "Override language of files by extension (e.g. `inc=c-header`, `h=c++-header`)"
+        )]
+        lang_map: Vec<String>,
+        #[clap(flatten)]
+        rule_options: RuleOptions,
+    },
+    #[clap(about = "List files to be processed with their languages")]
+    ListFiles {
+        #[clap(flatten)]
+        selection: Selection,
+    },
+    #[clap(about = "Print the report of `patch` without modifying files")]
+    Report {
+        #[clap(flatten)]
+        options: Options,
+        #[clap(
+            long = "output",
+            help = "Write the report to the file instead of stdout"
+        )]
+        output: Option<PathBuf>,
+    },
+    #[clap(about = "Write include graphs of translation units recorded in line markers of -E")]
+    Deps {
+        #[clap(flatten)]
+        selection: Selection,
+        #[clap(
+            long = "format",
+            help = "Graphviz DOT, JSON, or Makefile-style depfiles",
+            value_enum,
+            default_value = "dot"
+        )]
+        format: DepsFormat,
+        #[clap(
+            long = "output",
+            help = "Write the project-wide graph to the file instead of stdout"
+        )]
+        output: Option<PathBuf>,
+        #[clap(
+            long = "per-tu",
+            help = "Also write the graph of each translation unit to files in the directory"
+        )]
+        per_tu: Option<PathBuf>,
+        #[clap(long = "project-only", help = "Exclude system headers")]
+        project_only: bool,
+    },
+    #[clap(about = "Merge translation units into one source file in the style of sqlite3.c")]
+    Amalgamate {
+        #[clap(flatten)]
+        selection: Selection,
+        #[clap(
+            long = "output",
+            help = "Write the merged source to the file instead of stdout"
+        )]
+        output: Option<PathBuf>,
+        #[clap(
+            long = "report",
+            help = "Write merged files, hoisted includes and static symbol collisions in JSON"
+        )]
+        report: Option<PathBuf>,
+    },
+    #[clap(about = "Find the minimal set of rule edits in the file that breaks compilation")]
+    Bisect {
+        file: PathBuf,
+        #[clap(long = "compile-commands", help = "Path to compile_commands.json")]
+        compile_commands: PathBuf,
+        #[clap(
+            long = "lang-map",
+            help = "Override language of files by extension (e.g. `inc=c-header`, `h=c++-header`)"
+        )]
+        lang_map: Vec<String>,
+        #[clap(flatten)]
+        rule_options: RuleOptions,
+    },
 }
 
-impl From<shell_words::ParseError> for Error {
-    fn from(error: shell_words::ParseError) -> Self {
-        Error::ShellWordsParseError(error)
+fn print_edits(file: &Path, edits: &[RuleEdit]) {
+    for edit in edits.iter() {
+        println!(
+            "{}:{}:{}: [{}] {:?} -> {:?}\n    {}",
+            file.display(),
+            edit.position.line,
+            edit.position.column,
+            edit.rule,
+            edit.original,
+            edit.replacement,
+            edit.line.trim()
+        );
     }
 }
 
-fn preprocessor(command: &CompileCommand) -> Result<()> {
-    let mut args = if let Some(ref arguments) = command.arguments {
-        arguments.clone()
-    } else if let Some(ref command) = command.command {
-        shell_words::split(command)?
-    } else {
-        return Err(Error::CommandFormatError);
-    };
-    assert_ne!(args.len(), 0);
-    trace!("preprocessor: args={:?}", args);
-
-    #[derive(Debug)]
-    struct ReplaceTargetOption {
-        c: Option<usize>,
-        o: Option<usize>,
-    }
-    let replace_target_option: ReplaceTargetOption = (|args: &Vec<String>| -> ReplaceTargetOption {
-        let o = args.iter().position(|v| v == &String::from("-o"));
-        match args.iter().position(|v| v == &String::from("-c")) {
-            Some(c) => ReplaceTargetOption { c: Some(c), o },
-            None => ReplaceTargetOption { c: None, o },
+fn print_annotated_lines(lines: &[AnnotatedLine]) {
+    for line in lines.iter() {
+        let context = match (&line.context, line.source_line) {
+            (Some(context), Some(source_line)) => {
+                format!("{}:{}", context.path().display(), source_line)
+            }
+            (Some(context), None) => format!("{}", context.path().display()),
+            (None, _) => String::from("-"),
+        };
+        if let Some(ref directive) = line.restored_include {
+            println!(
+                "-\tinsert\tincluded-header\tforced\t{}\t{}",
+                context, directive
+            );
         }
-    })(&args);
-    trace!(
-        "preprocessor: replace_target_option={:?}",
-        replace_target_option
-    );
-    #[allow(non_snake_case)]
-    let option_E = String::from("-E");
-    if let Some(c) = replace_target_option.c {
-        args[c] = option_E.clone();
-    }
-    if let Some(o) = replace_target_option.o {
-        args.remove(o + 1);
-        args.remove(o);
-    }
-    if replace_target_option.c.is_none() {
-        args.push(option_E);
+        println!(
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            line.number,
+            if line.keep { "keep" } else { "drop" },
+            line.line.kind(),
+            if line.forced_include {
+                "forced"
+            } else if line.system {
+                "system"
+            } else {
+                "project"
+            },
+            context,
+            line.text
+        );
     }
+}
 
-    args.push(String::from("-dI")); // Preserve include statements
-    args.push(String::from("-C")); // Preserve comments
-
-    let output = Command::new(&args[0])
-        .args(&args[1..])
-        .current_dir(&command.directory)
-        .output()?;
-    if !output.stderr.is_empty() {
-        let mut stdout = io::stdout().lock();
-        stdout.write_all(&output.stderr)?;
+fn inspect(
+    file: &Path,
+    preprocessed: bool,
+    format: OutputFormat,
+    lang_map: &[String],
+    rule_options: &RuleOptions,
+) -> special_patch::Result<()> {
+    let preprocessed = preprocessed
+        || file
+            .extension()
+            .is_some_and(|v| v == "E" || v == "i" || v == "ii");
+    if preprocessed {
+        let lines = Preprocessor::annotate(&fs::read_to_string(file)?, &ForcedIncludes::default())?;
+        match format {
+            OutputFormat::Text => print_annotated_lines(&lines),
+            OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&lines)?),
+        }
+    } else {
+        let edits = special_patch::inspect_file(file, lang_map, rule_options)?;
+        match format {
+            OutputFormat::Text => print_edits(file, &edits),
+            OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&edits)?),
+        }
     }
-    output.status.exit_ok()?;
-
-    let patched = String::from_utf8(output.stdout).expect("Invalid UTF-8 sequence");
-    assert_ne!(patched.len(), 0);
-    let patched = Preprocessor::preprocess(&patched).unwrap();
-    let mut patched_file = File::create(&command.file)?;
-    patched_file.write_all(&patched.as_bytes())?;
-
     Ok(())
 }
 
-fn open_file(path: &PathBuf) -> String {
-    fs::read_to_string(path).expect(format!("Failed to read file: {}", path.display()).as_str())
-}
-
-fn save_file(path: &PathBuf, patched: Option<String>) {
-    if let Some(patched) = patched {
-        let file =
-            File::create(path).expect(format!("Failed to open file: {}", path.display()).as_str());
-        let mut writer = BufWriter::new(file);
-        write!(writer, "{}", patched).expect("Failed to write patched code");
+/// Depfiles of each translation unit, or the graph merging all translation units
+fn render_graphs(
+    format: DepsFormat,
+    graphs: &[(CompileCommand, IncludeGraph)],
+) -> special_patch::Result<String> {
+    if format == DepsFormat::Make {
+        return Ok(graphs
+            .iter()
+            .filter_map(|(command, graph)| Some(graph.to_depfile(&command.output()?)))
+            .collect::<Vec<String>>()
+            .join("\n"));
     }
-}
-
-fn apply(
-    re: &Regex,
-    original: String,
-    change_to: &str,
-    filter: fn(Option<Captures>) -> bool,
-) -> Option<String> {
-    if filter(re.captures(original.as_str())) {
-        return None;
+    let mut merged = IncludeGraph::default();
+    for (_, graph) in graphs.iter() {
+        merged.merge(graph.clone());
     }
-    let patched = re.replace_all(original.as_str(), change_to);
-    Some(String::from(patched))
+    Ok(match format {
+        DepsFormat::Json => serde_json::to_string_pretty(&merged)? + "\n",
+        _ => merged.to_dot(),
+    })
 }
 
-fn no_check(caps: Option<Captures>) -> bool {
-    if caps.is_none() {
-        return true;
+fn deps(
+    selection: &Selection,
+    format: DepsFormat,
+    output: Option<&Path>,
+    per_tu: Option<&Path>,
+    project_only: bool,
+) -> special_patch::Result<()> {
+    let graphs: Vec<(CompileCommand, IncludeGraph)> = special_patch::include_graphs(selection)?
+        .into_iter()
+        .map(|(command, graph)| {
+            if project_only {
+                (command, graph.project_only())
+            } else {
+                (command, graph)
+            }
+        })
+        .collect();
+    if let Some(dir) = per_tu {
+        fs::create_dir_all(dir)?;
+        for (index, graph) in graphs.iter().enumerate() {
+            // Prefix index since translation units in different directories may share names
+            let name = format!(
+                "{}-{}.{}",
+                index,
+                graph
+                    .0
+                    .file
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy(),
+                format.extension()
+            );
+            fs::write(
+                dir.join(name),
+                render_graphs(format, std::slice::from_ref(graph))?,
+            )?;
+        }
+    }
+    let content = render_graphs(format, &graphs)?;
+    match output {
+        Some(path) => fs::write(path, content)?,
+        None => print!("{}", content),
     }
-    false
+    Ok(())
 }
 
-fn double_quote_exists(caps: Option<Captures>) -> bool {
-    if let Some(caps) = caps {
-        println!("{:?}", caps);
-        caps.get(1).unwrap().as_str().contains("\"") || caps.get(3).unwrap().as_str().contains("\"")
-    } else {
-        true
+fn amalgamate(
+    selection: &Selection,
+    output: Option<&Path>,
+    report: Option<&Path>,
+) -> special_patch::Result<()> {
+    let amalgamation = special_patch::amalgamate(selection)?;
+    for (name, files) in amalgamation.static_collisions.iter() {
+        warn!(
+            "Static symbol defined in multiple files: name={:?}, files={:?}",
+            name, files
+        );
+    }
+    match output {
+        Some(path) => fs::write(path, &amalgamation.text)?,
+        None => print!("{}", amalgamation.text),
+    }
+    if let Some(path) = report {
+        fs::write(path, serde_json::to_string_pretty(&amalgamation)?)?;
     }
+    Ok(())
 }
 
-trait Util {
-    fn is_to_be_patched(&self) -> bool;
-    fn is_header_file(&self) -> bool;
-    fn is_source_file(&self) -> bool;
+fn print_bisect_outcome(file: &Path, outcome: &BisectOutcome) {
+    match outcome {
+        BisectOutcome::OriginalFails { .. } => {
+            println!("Original file does not compile: {}", file.display());
+        }
+        BisectOutcome::Compiles { edits } => {
+            println!("File compiles with all {} edits: {}", edits, file.display());
+        }
+        BisectOutcome::Minimal {
+            total,
+            edits,
+            diagnostics,
+        } => {
+            println!(
+                "{} of {} edits break compilation of {}:",
+                edits.len(),
+                total,
+                file.display()
+            );
+            print_edits(file, edits);
+            println!("{}", diagnostics);
+        }
+    }
 }
 
-impl Util for PathBuf {
-    fn is_to_be_patched(&self) -> bool {
-        self.is_header_file() || self.is_source_file()
+fn patch(options: &Options, report_path: Option<&Path>) -> special_patch::Result<()> {
+    let report = special_patch::run(options)?;
+    if let Some(path) = report_path {
+        report.save(path)?;
     }
+    Ok(())
+}
 
-    fn is_header_file(&self) -> bool {
-        match self.extension().and_then(OsStr::to_str) {
-            Some("h") | Some("hpp") => true,
-            _ => false,
+fn restore(files: &[PathBuf], backup_dir: &Path) -> special_patch::Result<()> {
+    let backup = Backup::new(backup_dir);
+    let files = if files.is_empty() {
+        backup.files()?
+    } else {
+        files
+            .iter()
+            .map(|v| v.canonicalize())
+            .collect::<Result<_, _>>()?
+    };
+    for file in files.iter() {
+        if backup.restore(file)? {
+            println!("{}", file.display());
+        } else {
+            warn!("No backup of file: file={:?}", file);
         }
     }
+    Ok(())
+}
 
-    fn is_source_file(&self) -> bool {
-        match self.extension().and_then(OsStr::to_str) {
-            Some("c") | Some("cpp") | Some("cc") => true,
-            _ => false,
-        }
+fn report(options: &Options, output: Option<&Path>) -> special_patch::Result<()> {
+    let options = Options {
+        dry_run: true,
+        ..options.clone()
+    };
+    let report: Report = special_patch::run(&options)?;
+    match output {
+        Some(path) => report.save(path)?,
+        None => println!("{}", serde_json::to_string_pretty(&report)?),
     }
+    Ok(())
 }
 
-fn main() {
+fn main() -> ExitCode {
     env_logger::init();
 
     let args = Cli::parse();
     info!("args = {:?}", env::args());
 
-    let compile_commands = fs::read_to_string(&args.compile_commands)
-        .expect(format!("Failed to open file: {:?}", &args.compile_commands).as_str());
-    let compile_commands: Vec<CompileCommand> =
-        serde_json::from_str(&compile_commands).expect("Failed to parse");
-    assert!(compile_commands.len() > 0);
-
-    // Filter out commands for same file
-    let compile_commands =
-        {
-            let mut unduplicated_compile_commands = Vec::new();
-            let mut done_list = HashSet::new();
-            'command: for command in compile_commands.iter() {
-                if done_list.contains(&command.file) {
-                    trace!(
-                    "Another command for same file. Skip: file={:?}, arguments={:?}, command={:?}",
-                    command.file, command.arguments, command.command
-                );
-                    continue 'command;
-                }
-                for ignore_path in args.ignore.iter() {
-                    if command
-                        .file
-                        .as_path()
-                        .strip_prefix(&command.directory)
-                        .unwrap()
-                        .starts_with(ignore_path)
-                    {
-                        trace!("Ignore command: file={:?}", command.file);
-                        continue 'command;
-                    }
-                }
-                done_list.insert(&command.file);
-                unduplicated_compile_commands.push(command);
-            }
-            unduplicated_compile_commands
-        };
-    info!("Total #commands: {}", compile_commands.len());
-
-    // Execute compile_commands.json-depend process
-    {
-        let result: Vec<_> = compile_commands
-            .par_iter()
-            .map(|command| -> Result<()> {
-                trace!("command.file={:?}", command.file);
-                if !command.file.is_source_file() {
-                    return Ok(());
-                }
-
-                // Apply preprocessor
-                if args.preprocessor {
-                    preprocessor(command)?;
-                }
-
-                Ok(())
-            })
-            .collect();
-        let _ = result.iter().map(|v| {
-            v.as_ref().map_err(|err| {
-                error!(
-                    "Failed to preprocess files in compile_commands.json: {:?}",
-                    err
-                )
-            })
-        });
-        let _ = result.iter().map(|v| {
-            v.as_ref()
-                .expect("Failed to process files in compile_commands.json")
-        });
-    }
-
-    let files_from_compile_commands: HashSet<PathBuf> = compile_commands
-        .iter()
-        .map(|v| v.file.clone())
-        .filter(|v| v.is_to_be_patched())
-        .collect();
-
-    let target_files: Vec<&PathBuf> = files_from_compile_commands
-        .iter()
-        .chain(args.files.iter())
-        .collect();
-    let result: Vec<_> = target_files
-        .par_iter()
-        .map(|file_path| -> Result<()> {
-            trace!("file_path={:?}", file_path);
-            let error_message = format!("Failed to operate file: {:?}", file_path);
-
-            // Insert include file
-            if let Some(ref header_name) = args.include {
-                let orig = File::open(file_path).expect(error_message.as_str());
-                let mut reader = BufReader::new(orig);
-                let mut buf = Vec::new();
-                reader.read_to_end(&mut buf).expect(error_message.as_str());
-
-                let file = File::create(&file_path).expect(error_message.as_str());
-                let mut writer = BufWriter::new(file);
-                write!(writer, "#include <{}>\n", header_name)
-                    .expect("Failed to add include directive");
-                writer
-                    .write_all(buf.as_slice())
-                    .expect("Failed to write patched code");
-            }
-
-            // Wrap NULL with brackets
-            {
-                let re = Regex::new(r"([^\w^\(])NULL([^\w^\)])").unwrap();
-                let patched = apply(&re, open_file(file_path), "$1(NULL)$2", no_check);
-                save_file(file_path, patched);
-            }
-
-            // Wipeout constexpr functions
-            {
-                // Un-constexpr functions
-                let re = Regex::new(r"constexpr\s(.*(\r)?(\n)?(\s*)\{)").unwrap();
-                let patched = apply(&re, open_file(file_path), "$1", no_check);
-                save_file(file_path, patched);
-
-                // Un-constexpr objects
-                if file_path.is_source_file() {
-                    let re = Regex::new(r"static constexpr\s(.*;)").unwrap();
-                    let patched = apply(&re, open_file(file_path), "$1", no_check);
-                    save_file(file_path, patched);
-                }
-            }
-
-            // Escape single quotes in const char for yaml string
-            {
-                // Case: ... 'abc' ...
-                {
-                    let patched = escape_single_quotes_in_const_char(open_file(file_path));
-                    save_file(file_path, patched);
-                }
-
-                // Case: ... can't ...
-                {
-                    let patched = escape_single_quote_in_const_char(open_file(file_path));
-                    save_file(file_path, patched);
+    let result = match args.command {
+        Command::Patch {
+            ref options,
+            ref report,
+        } => patch(options, report.as_deref()),
+        Command::Preprocess {
+            selection,
+            marker,
+            prune,
+            protect_macro,
+            forced_includes,
+            rewritten_compile_commands,
+            emit_defines,
+            checks,
+            backup,
+            ref report,
+        } => {
+            let options = Options {
+                selection,
+                preprocessor: true,
+                prune,
+                protect_macro,
+                forced_includes,
+                rewritten_compile_commands,
+                emit_defines,
+                rule_options: RuleOptions {
+                    rules: vec![],
+                    ..Default::default()
+                },
+                marker,
+                checks,
+                backup,
+                ..Default::default()
+            };
+            patch(&options, report.as_deref())
+        }
+        Command::Restore {
+            ref files,
+            ref backup_dir,
+        } => restore(files, backup_dir),
+        Command::Inspect {
+            ref file,
+            preprocessed,
+            format,
+            ref lang_map,
+            ref rule_options,
+        } => inspect(file, preprocessed, format, lang_map, rule_options),
+        Command::ListFiles { ref selection } => special_patch::list_files(selection).map(|files| {
+            for file in files.iter() {
+                match file.file_type {
+                    Some(file_type) => println!("{}\t{}", file.path.display(), file_type),
+                    None => println!("{}\t-", file.path.display()),
                 }
             }
-
-            Ok(())
-        })
-        .collect();
-    let _ = result.iter().map(|v| {
-        v.as_ref()
-            .map_err(|err| error!("Failed to process file: {:?}", err))
-    });
-}
-
-fn escape_single_quote_in_const_char(original: String) -> Option<String> {
-    let re = Regex::new("\"(.*)n't (.*)\"").unwrap();
-    apply(&re, original, "\"${1}n''t ${2}\"", no_check)
-}
-
-fn escape_single_quotes_in_const_char(original: String) -> Option<String> {
-    let re = Regex::new("\"(.*?)\\\\?'([^\"\n]{2,}?)\\\\?'(.*?)\"").unwrap();
-    apply(&re, original, "\"$1''$2''$3\"", double_quote_exists)
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    macro_rules! test_case {
-        ($func:expr, $original:expr, $patched:expr) => {
-            assert_eq!($func(String::from($original)), $patched);
-        };
-    }
-
-    #[test]
-    fn single_quote_should_escape() {
-        test_case!(
-            escape_single_quotes_in_const_char,
-            "let test = \"test 'ab'.\"",
-            Some(String::from("let test = \"test ''ab''.\""))
-        );
-
-        test_case!(
-            escape_single_quote_in_const_char,
-            "\"Can't stop typing.\"",
-            Some(String::from("\"Can''t stop typing.\""))
-        );
-    }
-
-    #[test]
-    fn single_quote_should_not_escape() {
-        test_case!(
-            escape_single_quotes_in_const_char,
-            "{\"text\", OPT_TEXT, '-', \"Print as text\"}",
-            None
-        );
-        test_case!(
-            escape_single_quotes_in_const_char,
-            "{\"select\", OPT_SELECT_NAME, 's', \"Select a single algorithm\"},",
-            None
-        );
-        test_case!(escape_single_quotes_in_const_char, "{ OPT_SECTION_STR, 1, '-', \"Random state\" \" options:\n\" }, {\"rand\", OPT_R_RAND, 's', \"Load the given file(s) into the random number generator\"}, {\"writerand\", OPT_R_WRITERAND, '>', \"Write random data to the specified file\"}", None);
-
-        test_case!(escape_single_quotes_in_const_char, "\"\n'abc'\"", None);
-        test_case!(escape_single_quotes_in_const_char, "\"'ab\nc'\"", None);
-
-        test_case!(escape_single_quotes_in_const_char, "\"'ab\nc'\"", None);
+        }),
+        Command::Report {
+            ref options,
+            ref output,
+        } => report(options, output.as_deref()),
+        Command::Deps {
+            ref selection,
+            format,
+            ref output,
+            ref per_tu,
+            project_only,
+        } => deps(
+            selection,
+            format,
+            output.as_deref(),
+            per_tu.as_deref(),
+            project_only,
+        ),
+        Command::Amalgamate {
+            ref selection,
+            ref output,
+            ref report,
+        } => amalgamate(selection, output.as_deref(), report.as_deref()),
+        Command::Bisect {
+            ref file,
+            ref compile_commands,
+            ref lang_map,
+            ref rule_options,
+        } => {
+            let selection = Selection {
+                compile_commands: compile_commands.clone(),
+                lang_map: lang_map.clone(),
+                ..Default::default()
+            };
+            special_patch::bisect_file(&selection, rule_options, file)
+                .map(|outcome| print_bisect_outcome(file, &outcome))
+        }
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            error!("Failed: err={:?}", err);
+            ExitCode::FAILURE
+        }
     }
 }
@@ -0,0 +1,100 @@
+use crate::rule::Rule;
+
+use std::collections::BTreeSet;
+
+const MARKER_PREFIX: &str = "/* special-patch: applied=";
+const MARKER_SUFFIX: &str = " */";
+const SNIPPET_MARKER_PREFIX: &str = "/* special-patch: inserted=";
+
+/// Comment put on the line before code inserted by `--prelude` or `--epilogue` (`name`), so that
+/// it is detected even if the snippet renders differently (e.g. with another `{{index}}`)
+pub fn snippet_marker(name: &str) -> String {
+    format!("{}{}{}", SNIPPET_MARKER_PREFIX, name, MARKER_SUFFIX)
+}
+
+/// Whether `source` has code marked by `snippet_marker(name)`
+pub fn has_snippet(source: &str, name: &str) -> bool {
+    let marker = snippet_marker(name);
+    source.lines().any(|v| v.trim() == marker)
+}
+
+/// Rules recorded in the marker comment at the end of `source`.
+pub fn applied_rules(source: &str) -> BTreeSet<Rule> {
+    source
+        .lines()
+        .rev()
+        .find(|v| !v.trim().is_empty())
+        .and_then(|v| v.strip_prefix(MARKER_PREFIX))
+        .and_then(|v| v.strip_suffix(MARKER_SUFFIX))
+        .map(|v| v.split(',').filter_map(|v| v.parse().ok()).collect())
+        .unwrap_or_default()
+}
+
+/// Offset of the marker comment at the end of `source`, or length of `source` if it has no marker
+pub fn marker_offset(source: &str) -> usize {
+    let body = source.trim_end();
+    let start = body.rfind('\n').map_or(0, |v| v + 1);
+    if body[start..].starts_with(MARKER_PREFIX) {
+        start
+    } else {
+        source.len()
+    }
+}
+
+/// Replace the marker comment at the end of `source` with one recording `rules`.
+/// The marker is appended as the last line so that line numbers of the code do not change.
+pub fn set_applied_rules(source: &str, rules: &BTreeSet<Rule>) -> String {
+    let mut result = match source.trim_end().rsplit_once('\n') {
+        Some((body, last_line)) if last_line.starts_with(MARKER_PREFIX) => format!("{}\n", body),
+        None if source.starts_with(MARKER_PREFIX) => String::new(),
+        _ if source.is_empty() || source.ends_with('\n') => source.to_string(),
+        _ => format!("{}\n", source),
+    };
+    let rules: Vec<&str> = rules.iter().map(Rule::name).collect();
+    result.push_str(&format!(
+        "{}{}{}\n",
+        MARKER_PREFIX,
+        rules.join(","),
+        MARKER_SUFFIX
+    ));
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn marker() {
+        let rules = BTreeSet::from([Rule::Null, Rule::Constexpr]);
+        let source = "int main() {}";
+        assert_eq!(applied_rules(source), BTreeSet::new());
+
+        let patched = set_applied_rules(source, &rules);
+        assert_eq!(
+            patched,
+            "int main() {}\n/* special-patch: applied=null,constexpr */\n"
+        );
+        assert_eq!(applied_rules(&patched), rules);
+
+        let rules = BTreeSet::from([Rule::Null, Rule::Constexpr, Rule::SingleQuote]);
+        let patched = set_applied_rules(&patched, &rules);
+        assert_eq!(
+            patched,
+            "int main() {}\n/* special-patch: applied=null,constexpr,single-quote */\n"
+        );
+        assert_eq!(applied_rules(&patched), rules);
+    }
+
+    #[test]
+    fn snippet_marker() {
+        let source = format!("{}\nint index = 1;\n", super::snippet_marker("prelude"));
+        assert_eq!(
+            source,
+            "/* special-patch: inserted=prelude */\nint index = 1;\n"
+        );
+        assert!(has_snippet(&source, "prelude"));
+        assert!(!has_snippet(&source, "epilogue"));
+        assert_eq!(applied_rules(&source), BTreeSet::new());
+    }
+}
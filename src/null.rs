@@ -0,0 +1,217 @@
+//! Normalization of null pointer constants.
+
+use crate::edit::Edit;
+use crate::language::FileType;
+use crate::tokenizer::{self, Token, TokenKind};
+
+use clap::ValueEnum;
+use regex::Regex;
+use std::ops::Range;
+
+/// Spelling of null pointer constants
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum NullStyle {
+    /// `(NULL)`
+    Paren,
+    /// `((void*)0)` (C only)
+    VoidPtr,
+    /// `nullptr` (C++ only)
+    Nullptr,
+}
+
+impl NullStyle {
+    /// Falls back to `Paren` if the style is not valid in the language of the file.
+    pub fn for_file_type(self, file_type: Option<FileType>) -> Self {
+        let is_cxx = file_type.is_some_and(|v| v.is_cxx());
+        match self {
+            NullStyle::Nullptr if !is_cxx => NullStyle::Paren,
+            // `void *` is not implicitly converted to other pointer types in C++
+            NullStyle::VoidPtr if is_cxx => NullStyle::Paren,
+            style => style,
+        }
+    }
+
+    pub fn spelling(&self) -> &'static str {
+        match self {
+            NullStyle::Paren => "(NULL)",
+            NullStyle::VoidPtr => "((void*)0)",
+            NullStyle::Nullptr => "nullptr",
+        }
+    }
+}
+
+/// Keywords which may directly precede a parenthesized expression
+const EXPRESSION_KEYWORDS: [&str; 6] = ["return", "case", "throw", "else", "do", "co_return"];
+
+/// Rewrite `NULL` in `style`, including `NULL` in macro definitions. Definitions and tests of
+/// `NULL` itself (e.g. `#ifndef NULL`) are kept. If `zero` is set, `0` in pointer casts
+/// (`(char *)0`) and pointer initializations (`char *p = 0;`) are rewritten too.
+pub fn null_edits(source: &str, style: NullStyle, zero: bool) -> Vec<Edit> {
+    let mut ranges = Vec::new();
+    collect_ranges(source, 0, style, zero, &mut ranges);
+    ranges
+        .into_iter()
+        .map(|range| Edit::new(range, style.spelling()))
+        .collect()
+}
+
+/// Collect byte ranges of `NULL` and `0` to be rewritten
+fn collect_ranges(
+    source: &str,
+    base: usize,
+    style: NullStyle,
+    zero: bool,
+    ranges: &mut Vec<Range<usize>>,
+) {
+    let define = Regex::new(r"^#\s*define\s+(\w+)(\([^)]*\))?").unwrap();
+    let tokens = tokenizer::significant_tokens(source);
+    for (i, token) in tokens.iter().enumerate() {
+        let previous = |n: usize| i.checked_sub(n).map(|v| &tokens[v]);
+        let next = |n: usize| tokens.get(i + n);
+        match token.kind {
+            TokenKind::Directive => {
+                // Rewrite the replacement list of macros other than `NULL`
+                if let Some(caps) = define.captures(token.text) {
+                    if &caps[1] != "NULL" {
+                        let body = caps.get(0).unwrap().end();
+                        collect_ranges(
+                            &token.text[body..],
+                            base + token.start + body,
+                            style,
+                            zero,
+                            ranges,
+                        );
+                    }
+                }
+            }
+            TokenKind::Identifier if token.is("NULL") => {
+                // `(NULL)` is already in the paren style, even as the only argument of a call
+                let wrapped = style == NullStyle::Paren
+                    && previous(1).is_some_and(|v| v.is("("))
+                    && next(1).is_some_and(|v| v.is(")"));
+                if !wrapped {
+                    ranges.push(base + token.start..base + token.end());
+                }
+            }
+            TokenKind::Number if zero && token.is("0") => {
+                // `(T *)0`, but not `(void *)0` which is already a null pointer constant
+                let cast = previous(1).is_some_and(|v| v.is(")"))
+                    && previous(2).is_some_and(|v| v.is("*"))
+                    && !(previous(3).is_some_and(|v| v.is("void"))
+                        && previous(4).is_some_and(|v| v.is("(")))
+                    && is_cast(&tokens, i - 1);
+                // `T *p = 0;`
+                let initialization = previous(1).is_some_and(|v| v.is("="))
+                    && previous(2).is_some_and(|v| v.kind == TokenKind::Identifier)
+                    && previous(3).is_some_and(|v| v.is("*"))
+                    && next(1).is_some_and(|v| v.is(";") || v.is(",") || v.is(")"));
+                if cast || initialization {
+                    ranges.push(base + token.start..base + token.end());
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Whether parentheses following `token` are not a parenthesized expression but e.g. arguments
+/// of a function call or condition of `if`
+fn is_callee(token: &Token) -> bool {
+    match token.kind {
+        TokenKind::Identifier => !EXPRESSION_KEYWORDS.contains(&token.text),
+        _ => token.is(")") || token.is("]") || token.is(">"),
+    }
+}
+
+/// Whether `)` at `close` closes a cast to a pointer type such as `(struct foo *)`
+fn is_cast(tokens: &[Token], close: usize) -> bool {
+    let mut i = close;
+    while i > 0 {
+        i -= 1;
+        let token = &tokens[i];
+        match token.text {
+            "(" => return i == 0 || !is_callee(&tokens[i - 1]),
+            "*" | "const" | "volatile" | "::" | "<" | ">" | "," => (),
+            _ if token.kind == TokenKind::Identifier => (),
+            _ => return false,
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::language::{FileKind, Language};
+
+    fn normalize(source: &str, style: NullStyle) -> String {
+        crate::edit::apply(source, &null_edits(source, style, false))
+    }
+
+    #[test]
+    fn paren() {
+        assert_eq!(
+            normalize(
+                "NULL;\nif (p == NULL) f(NULL, NULL);\nreturn (NULL);\nNULL",
+                NullStyle::Paren
+            ),
+            "(NULL);\nif (p == (NULL)) f((NULL), (NULL));\nreturn (NULL);\n(NULL)"
+        );
+        // Idempotent
+        let source = "p = NULL; f(NULL);";
+        let patched = normalize(source, NullStyle::Paren);
+        assert!(null_edits(&patched, NullStyle::Paren, false).is_empty());
+    }
+
+    #[test]
+    fn styles() {
+        assert_eq!(
+            normalize("char *p = NULL;", NullStyle::VoidPtr),
+            "char *p = ((void*)0);"
+        );
+        assert_eq!(
+            normalize(
+                "if (!NULL_CHECK(p) && p != NULL) {} return (NULL);",
+                NullStyle::Nullptr
+            ),
+            "if (!NULL_CHECK(p) && p != nullptr) {} return (nullptr);"
+        );
+    }
+
+    #[test]
+    fn macros() {
+        let source = "#ifndef NULL\n#define NULL ((void*)0)\n#endif\n#define IS_NULL(p) ((p) == NULL)\nconst char *s = \"NULL\"; // NULL\n";
+        assert_eq!(
+            normalize(source, NullStyle::Paren),
+            source.replace("== NULL", "== (NULL)")
+        );
+    }
+
+    #[test]
+    fn zero() {
+        let source =
+            "char *p = 0, *q = (char *)0; int n = 0; f((struct s *)0, a * 0); virtual void g() = 0;";
+        assert_eq!(
+            crate::edit::apply(source, &null_edits(source, NullStyle::Paren, true)),
+            "char *p = (NULL), *q = (char *)(NULL); int n = 0; f((struct s *)(NULL), a * 0); virtual void g() = 0;"
+        );
+
+        // Idempotent in every style
+        let source = format!("{} char *r = (char *)(NULL); void *v = (void *)0;", source);
+        for style in [NullStyle::Paren, NullStyle::VoidPtr] {
+            let patched = crate::edit::apply(&source, &null_edits(&source, style, true));
+            assert_eq!(null_edits(&patched, style, true), vec![], "{:?}", style);
+        }
+    }
+
+    #[test]
+    fn for_file_type() {
+        let c = Some(FileType::new(Language::C, FileKind::Source));
+        let cxx = Some(FileType::new(Language::Cxx, FileKind::Header));
+        assert_eq!(NullStyle::Nullptr.for_file_type(c), NullStyle::Paren);
+        assert_eq!(NullStyle::Nullptr.for_file_type(cxx), NullStyle::Nullptr);
+        assert_eq!(NullStyle::VoidPtr.for_file_type(c), NullStyle::VoidPtr);
+        assert_eq!(NullStyle::VoidPtr.for_file_type(cxx), NullStyle::Paren);
+        assert_eq!(NullStyle::Nullptr.for_file_type(None), NullStyle::Paren);
+    }
+}
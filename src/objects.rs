@@ -0,0 +1,150 @@
+use object::{Object, ObjectSection, ObjectSymbol, RelocationKind, RelocationTarget};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Relocation entry of a compared section. The target is named by its symbol, or by its section
+/// for section symbols, so that entries of different objects can be compared.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Relocation {
+    /// Offset in the concatenated contents of the sections sharing a name
+    pub offset: u64,
+    pub kind: RelocationKind,
+    pub addend: i64,
+    pub target: String,
+}
+
+/// Contents and relocations of a compared section
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ComparedSection {
+    pub data: Vec<u8>,
+    pub relocations: Vec<Relocation>,
+}
+
+fn relocation_target(file: &object::File, target: RelocationTarget) -> object::Result<String> {
+    let section = match target {
+        RelocationTarget::Symbol(index) => {
+            let symbol = file.symbol_by_index(index)?;
+            match symbol.section_index() {
+                Some(section) if symbol.name()?.is_empty() => section,
+                _ => return Ok(symbol.name()?.to_string()),
+            }
+        }
+        RelocationTarget::Section(section) => section,
+        _ => return Ok(String::new()),
+    };
+    Ok(file.section_by_index(section)?.name()?.to_string())
+}
+
+/// Code and data sections (e.g. `.text`, `.text.startup`, `.data.rel.ro`) keyed on their names.
+/// Contents of sections sharing a name are concatenated.
+pub fn compared_sections(data: &[u8]) -> object::Result<BTreeMap<String, ComparedSection>> {
+    let file = object::File::parse(data)?;
+    let mut sections: BTreeMap<String, ComparedSection> = BTreeMap::new();
+    for section in file.sections() {
+        let name = section.name()?;
+        if !(name.starts_with(".text") || name.starts_with(".data")) {
+            continue;
+        }
+        let compared = sections.entry(name.to_string()).or_default();
+        let base = compared.data.len() as u64;
+        for (offset, relocation) in section.relocations() {
+            compared.relocations.push(Relocation {
+                offset: base + offset,
+                kind: relocation.kind(),
+                addend: relocation.addend(),
+                target: relocation_target(&file, relocation.target())?,
+            });
+        }
+        compared.data.extend_from_slice(section.data()?);
+    }
+    Ok(sections)
+}
+
+/// Names of code and data sections which differ between two object files in contents or in
+/// relocations (e.g. call targets), including sections present in only one of them
+pub fn different_sections(original: &[u8], patched: &[u8]) -> object::Result<Vec<String>> {
+    let original = compared_sections(original)?;
+    let patched = compared_sections(patched)?;
+    let names: BTreeSet<&String> = original.keys().chain(patched.keys()).collect();
+    Ok(names
+        .into_iter()
+        .filter(|name| original.get(*name) != patched.get(*name))
+        .cloned()
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::{Path, PathBuf};
+    use std::process::Command;
+
+    fn compile(source: &str, args: &[&str], output: &str) -> Vec<u8> {
+        let output = std::env::temp_dir().join(format!(
+            "special-patch-objects-{}-{}",
+            std::process::id(),
+            output
+        ));
+        let status = Command::new("cc")
+            .args(["-x", "c", "-c", source, "-o"])
+            .arg(&output)
+            .args(args)
+            .current_dir(Path::new("./test/preprocessor"))
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let data = std::fs::read(&output).unwrap();
+        std::fs::remove_file(PathBuf::from(&output)).unwrap();
+        data
+    }
+
+    #[test]
+    fn different_sections() {
+        // Same as `make` in test/preprocessor
+        let original = compile("main.c", &[], "main.o");
+        let patched = compile("main.E.expected", &[], "main.expected.o");
+        assert!(compared_sections(&original).unwrap().contains_key(".text"));
+        assert_eq!(
+            super::different_sections(&original, &patched).unwrap(),
+            Vec::<String>::new()
+        );
+
+        let optimized = compile("main.c", &["-O2"], "main.O2.o");
+        assert!(!super::different_sections(&original, &optimized)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn different_relocations() {
+        let source = |name: &str, callee: &str| {
+            let path = std::env::temp_dir().join(format!(
+                "special-patch-objects-{}-{}.c",
+                std::process::id(),
+                name
+            ));
+            std::fs::write(
+                &path,
+                format!(
+                    "int f(void);\nint g(void);\nint h(void) {{ return {}(); }}\n",
+                    callee
+                ),
+            )
+            .unwrap();
+            path
+        };
+        let original_source = source("call-f", "f");
+        let patched_source = source("call-g", "g");
+        let original = compile(original_source.to_str().unwrap(), &[], "call-f.o");
+        let patched = compile(patched_source.to_str().unwrap(), &[], "call-g.o");
+        std::fs::remove_file(original_source).unwrap();
+        std::fs::remove_file(patched_source).unwrap();
+
+        // Only the call target differs
+        let sections = |data: &[u8]| compared_sections(data).unwrap()[".text"].clone();
+        assert_eq!(sections(&original).data, sections(&patched).data);
+        assert_eq!(
+            super::different_sections(&original, &patched).unwrap(),
+            vec![String::from(".text")]
+        );
+    }
+}
@@ -0,0 +1,386 @@
+use crate::backup;
+use crate::cache;
+use crate::constexpr::ConstexprVariables;
+use crate::injection::{DefinesStyle, IncludeStyle, Placement};
+use crate::null::NullStyle;
+use crate::patch::RuleConfig;
+use crate::preprocessor::ForcedIncludeMode;
+use crate::probe::Probe;
+use crate::rule::Rule;
+use crate::Result;
+
+use regex::Regex;
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+
+/// Files to process. Shared by all subcommands working on compile_commands.json.
+#[derive(clap::Args, Debug, Clone)]
+pub struct Selection {
+    #[clap(long = "compile-commands", help = "Path to compile_commands.json")]
+    pub compile_commands: PathBuf,
+    pub files: Vec<PathBuf>,
+    #[clap(
+        long = "ignore",
+        help = "Ignore files matching gitignore-style glob (relative to directory of each command, or current directory for FILES)"
+    )]
+    pub ignore: Vec<String>,
+    #[clap(long = "ignore-regex", help = "Ignore files matching regex")]
+    pub ignore_regex: Vec<String>,
+    #[clap(
+        long = "ignore-from",
+        help = "Read --ignore globs from file (one per line; lines prefixed with `re:` are regexes)"
+    )]
+    pub ignore_from: Vec<PathBuf>,
+    #[clap(
+        long = "only",
+        help = "Process only files matching gitignore-style glob (same base as --ignore)"
+    )]
+    pub only: Vec<String>,
+    #[clap(long = "only-regex", help = "Process only files matching regex")]
+    pub only_regex: Vec<String>,
+    #[clap(
+        long = "only-from",
+        help = "Read --only globs from file (one per line; lines prefixed with `re:` are regexes)"
+    )]
+    pub only_from: Vec<PathBuf>,
+    #[clap(
+        long = "lang-map",
+        help = "Override language of files by extension (e.g. `inc=c-header`, `h=c++-header`)"
+    )]
+    pub lang_map: Vec<String>,
+    #[clap(
+        long = "headers",
+        help = "Also process project headers included by sources in compile_commands.json"
+    )]
+    pub headers: bool,
+}
+
+impl Default for Selection {
+    fn default() -> Self {
+        Selection {
+            compile_commands: PathBuf::from("compile_commands.json"),
+            files: Vec::new(),
+            ignore: Vec::new(),
+            ignore_regex: Vec::new(),
+            ignore_from: Vec::new(),
+            only: Vec::new(),
+            only_regex: Vec::new(),
+            only_from: Vec::new(),
+            lang_map: Vec::new(),
+            headers: false,
+        }
+    }
+}
+
+/// Rules rewriting text and their options
+#[derive(clap::Args, Debug, Clone)]
+pub struct RuleOptions {
+    #[clap(
+        long = "rules",
+        help = "Rules to apply",
+        value_parser = Rule::parse_text_rule,
+        use_value_delimiter = true,
+        default_value = "null,constexpr,single-quote"
+    )]
+    pub rules: Vec<Rule>,
+    #[clap(
+        long = "constexpr-variables",
+        help = "How to rewrite constexpr variables",
+        value_enum,
+        default_value = "const"
+    )]
+    pub constexpr_variables: ConstexprVariables,
+    #[clap(
+        long = "null-style",
+        help = "Spelling of NULL: `(NULL)`, `((void*)0)` (C only) or `nullptr` (C++ only). Falls back to paren in other languages",
+        value_enum,
+        default_value = "paren"
+    )]
+    pub null_style: NullStyle,
+    #[clap(
+        long = "null-zero",
+        help = "Also rewrite 0 used as null pointer in casts (`(char *)0`) and pointer initializations (`char *p = 0;`)"
+    )]
+    pub null_zero: bool,
+    #[clap(
+        long = "probe",
+        help = "Insert the snippet at the start of function bodies (e.g. `PROBE(__func__, __FILE__, __LINE__);`). Enables the probe rule"
+    )]
+    pub probe: Option<String>,
+    #[clap(
+        long = "probe-function",
+        requires = "probe",
+        help = "Insert --probe only into functions whose names match regex"
+    )]
+    pub probe_function: Option<String>,
+    #[clap(
+        long = "probe-file",
+        requires = "probe",
+        help = "Insert --probe only into files whose paths match regex"
+    )]
+    pub probe_file: Option<String>,
+}
+
+impl Default for RuleOptions {
+    fn default() -> Self {
+        RuleOptions {
+            rules: Rule::DEFAULT_RULES.to_vec(),
+            constexpr_variables: ConstexprVariables::Const,
+            null_style: NullStyle::Paren,
+            null_zero: false,
+            probe: None,
+            probe_function: None,
+            probe_file: None,
+        }
+    }
+}
+
+impl RuleOptions {
+    pub fn rule_config(&self, marker: bool) -> Result<RuleConfig> {
+        let regex = |v: &Option<String>| v.as_deref().map(Regex::new).transpose();
+        let probe = match self.probe {
+            Some(ref snippet) => Some(Probe {
+                snippet: snippet.clone(),
+                function: regex(&self.probe_function)?,
+                file: regex(&self.probe_file)?,
+            }),
+            None => None,
+        };
+        let mut rules: BTreeSet<Rule> = self.rules.iter().cloned().collect();
+        if probe.is_some() {
+            rules.insert(Rule::Probe);
+        } else {
+            rules.remove(&Rule::Probe);
+        }
+        Ok(RuleConfig {
+            rules,
+            null_style: self.null_style,
+            null_zero: self.null_zero,
+            constexpr_variables: self.constexpr_variables,
+            probe,
+            marker,
+        })
+    }
+}
+
+/// Checks of patched translation units
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct Checks {
+    #[clap(
+        long = "verify",
+        help = "Check that patched translation units still compile, using their compile commands with -fsyntax-only"
+    )]
+    pub verify: bool,
+    #[clap(
+        long = "rollback-on-failure",
+        help = "Restore translation units failing --verify and their headers to the original",
+        requires = "verify"
+    )]
+    pub rollback_on_failure: bool,
+    #[clap(
+        long = "compare-objects",
+        help = "Compile translation units before and after patching and compare .text and .data sections of the objects"
+    )]
+    pub compare_objects: bool,
+}
+
+/// Where to keep original files for `restore`
+#[derive(clap::Args, Debug, Clone)]
+pub struct BackupOptions {
+    #[clap(
+        long = "backup-dir",
+        help = "Directory to keep original files for `restore`",
+        default_value = backup::DEFAULT_BACKUP_DIR
+    )]
+    pub backup_dir: PathBuf,
+    #[clap(long = "no-backup", help = "Do not keep original files")]
+    pub no_backup: bool,
+}
+
+impl Default for BackupOptions {
+    fn default() -> Self {
+        BackupOptions {
+            backup_dir: PathBuf::from(backup::DEFAULT_BACKUP_DIR),
+            no_backup: false,
+        }
+    }
+}
+
+/// Options of `run()`. Also used as command line arguments of the binary.
+#[derive(clap::Args, Debug, Clone)]
+pub struct Options {
+    #[clap(flatten)]
+    pub selection: Selection,
+    #[clap(
+        long = "preprocessor",
+        help = "Replace original source code with preprocessed one"
+    )]
+    pub preprocessor: bool,
+    #[clap(
+        long = "prune",
+        requires = "preprocessor",
+        help = "Remove static functions, static variables, prototypes, typedefs and struct definitions unreferenced in preprocessed C sources"
+    )]
+    pub prune: bool,
+    #[clap(
+        long = "protect-macro",
+        requires = "preprocessor",
+        help = "Keep invocations of the macro unexpanded by the preprocessor (e.g. MAGMA_LOG). The macro must be defined by a header forced by -include"
+    )]
+    pub protect_macro: Vec<String>,
+    #[clap(
+        long = "forced-includes",
+        help = "How to handle headers forced by -include: drop them keeping -include in compile commands (strip), restore one #include directive for compile commands without -include (directive), or inline them writing compile commands without -include and -imacros to --rewritten-compile-commands (inline)",
+        value_enum,
+        default_value = "strip"
+    )]
+    pub forced_includes: ForcedIncludeMode,
+    #[clap(
+        long = "rewritten-compile-commands",
+        help = "Where to write compile_commands.json without -include and -imacros of preprocessed files",
+        required_if_eq("forced-includes", "inline")
+    )]
+    pub rewritten_compile_commands: Option<PathBuf>,
+    #[clap(
+        long = "emit-defines",
+        requires = "preprocessor",
+        help = "Insert a block listing -D and -U flags of the compile command at the top of preprocessed files, as a comment (comment), or as #define directives for macros referenced by restored system headers and a comment for the others (define)",
+        value_enum
+    )]
+    pub emit_defines: Option<DefinesStyle>,
+    #[clap(flatten)]
+    pub rule_options: RuleOptions,
+    #[clap(
+        long = "include",
+        help = "Add include directive to files unless they already include the header"
+    )]
+    pub include: Vec<String>,
+    #[clap(
+        long = "include-style",
+        help = "Form of include directives added by --include",
+        value_enum,
+        default_value = "angle"
+    )]
+    pub include_style: IncludeStyle,
+    #[clap(
+        long = "include-placement",
+        help = "Where to add include directives by --include",
+        value_enum,
+        default_value = "top"
+    )]
+    pub include_placement: Placement,
+    #[clap(
+        long = "prelude",
+        help = "Insert content of the file to files. {{file}}, {{relative_path}} and {{index}} are replaced with path, relative path and index of each file"
+    )]
+    pub prelude: Option<PathBuf>,
+    #[clap(
+        long = "prelude-placement",
+        help = "Where to insert --prelude",
+        value_enum,
+        default_value = "after-guard"
+    )]
+    pub prelude_placement: Placement,
+    #[clap(
+        long = "epilogue",
+        help = "Insert content of the file at the end of files (before #endif of include guard). Same variables as --prelude are available"
+    )]
+    pub epilogue: Option<PathBuf>,
+    #[clap(
+        long = "canary-spec",
+        help = "Insert snippets at locations given by the YAML (or JSON) file before preprocessing. Entries not matching the files are errors"
+    )]
+    pub canary_spec: Option<PathBuf>,
+    #[clap(
+        long = "incremental",
+        help = "Skip files unchanged since last run with same arguments and rules"
+    )]
+    pub incremental: bool,
+    #[clap(
+        long = "cache",
+        help = "Path to cache file for --incremental",
+        default_value = cache::DEFAULT_CACHE_PATH
+    )]
+    pub cache: PathBuf,
+    #[clap(
+        long = "marker",
+        help = "Record applied rules in a comment at the end of files. Rules recorded in the comment are not applied again"
+    )]
+    pub marker: bool,
+    #[clap(flatten)]
+    pub checks: Checks,
+    #[clap(flatten)]
+    pub backup: BackupOptions,
+    /// Compute the report without writing files. Checks, backups and the cache are skipped.
+    #[clap(skip)]
+    pub dry_run: bool,
+}
+
+impl Default for Options {
+    /// Same as the defaults of command line arguments
+    fn default() -> Self {
+        Options {
+            selection: Selection::default(),
+            preprocessor: false,
+            prune: false,
+            protect_macro: Vec::new(),
+            forced_includes: ForcedIncludeMode::Strip,
+            rewritten_compile_commands: None,
+            emit_defines: None,
+            rule_options: RuleOptions::default(),
+            include: Vec::new(),
+            include_style: IncludeStyle::Angle,
+            include_placement: Placement::Top,
+            prelude: None,
+            prelude_placement: Placement::AfterGuard,
+            epilogue: None,
+            canary_spec: None,
+            incremental: false,
+            cache: PathBuf::from(cache::DEFAULT_CACHE_PATH),
+            marker: false,
+            checks: Checks::default(),
+            backup: BackupOptions::default(),
+            dry_run: false,
+        }
+    }
+}
+
+impl Options {
+    /// Tool version and options that change how files are patched
+    pub fn fingerprint(&self) -> String {
+        let snippet_hash = |path: &Option<PathBuf>| {
+            path.as_ref()
+                .map(|v| cache::hash_file(v).unwrap_or_default())
+        };
+        format!(
+            "version={} preprocessor={} prune={} protect_macro={:?} forced_includes={:?} emit_defines={:?} rules={:?} probe={:?} probe_function={:?} probe_file={:?} include={:?} include_style={:?} include_placement={:?} prelude={:?} prelude_placement={:?} epilogue={:?} canary_spec={:?} lang_map={:?} marker={} constexpr_variables={:?} null_style={:?} null_zero={}",
+            env!("CARGO_PKG_VERSION"),
+            self.preprocessor,
+            self.prune,
+            self.protect_macro,
+            self.forced_includes,
+            self.emit_defines,
+            self.rule_options.rules,
+            self.rule_options.probe,
+            self.rule_options.probe_function,
+            self.rule_options.probe_file,
+            self.include,
+            self.include_style,
+            self.include_placement,
+            snippet_hash(&self.prelude),
+            self.prelude_placement,
+            snippet_hash(&self.epilogue),
+            snippet_hash(&self.canary_spec),
+            self.selection.lang_map,
+            self.marker,
+            self.rule_options.constexpr_variables,
+            self.rule_options.null_style,
+            self.rule_options.null_zero
+        )
+    }
+
+    /// Rules rewriting text. `Rule::Preprocessor` is not included.
+    pub fn rule_config(&self) -> Result<RuleConfig> {
+        self.rule_options.rule_config(self.marker)
+    }
+}
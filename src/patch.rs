@@ -0,0 +1,348 @@
+use crate::constexpr::{self, ConstexprVariables};
+use crate::edit::{self, Edit};
+use crate::language::FileType;
+use crate::marker;
+use crate::null::{self, NullStyle};
+use crate::probe::{self, Probe};
+use crate::report::{FileReport, Position};
+use crate::rule::Rule;
+
+use regex::Captures;
+use regex::Regex;
+use regex::Replacer;
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::path::Path;
+
+/// Rules rewriting text and their options
+#[derive(Debug, Clone)]
+pub struct RuleConfig {
+    /// Rules to apply. `Rule::Preprocessor` is not applied to buffers, but recorded in the marker.
+    pub rules: BTreeSet<Rule>,
+    pub null_style: NullStyle,
+    pub null_zero: bool,
+    pub constexpr_variables: ConstexprVariables,
+    /// Snippet inserted by `Rule::Probe`. The rule is skipped if not given.
+    pub probe: Option<Probe>,
+    /// Record applied rules in a comment at the end of the buffer
+    pub marker: bool,
+}
+
+impl Default for RuleConfig {
+    fn default() -> Self {
+        RuleConfig {
+            rules: BTreeSet::from([Rule::Null, Rule::Constexpr, Rule::SingleQuote]),
+            null_style: NullStyle::Paren,
+            null_zero: false,
+            constexpr_variables: ConstexprVariables::Const,
+            probe: None,
+            marker: false,
+        }
+    }
+}
+
+impl RuleConfig {
+    /// Configuration for `path`. `Rule::Probe` is disabled if the path does not match the probe.
+    pub fn for_file(&self, path: &Path) -> RuleConfig {
+        let mut config = self.clone();
+        if !self.probe.as_ref().is_some_and(|v| v.matches_file(path)) {
+            config.rules.remove(&Rule::Probe);
+        }
+        config
+    }
+}
+
+/// Edit made by a rule, located in the source
+#[derive(Debug, Clone, Serialize)]
+pub struct RuleEdit {
+    pub rule: Rule,
+    pub position: Position,
+    /// Replaced text
+    pub original: String,
+    pub replacement: String,
+    /// Line of the replaced text
+    pub line: String,
+}
+
+impl RuleEdit {
+    pub fn new(source: &str, rule: Rule, edit: &Edit) -> Self {
+        let position = Position::new(source, edit.range.start);
+        RuleEdit {
+            rule,
+            position,
+            original: source[edit.range.clone()].to_string(),
+            replacement: edit.replacement.clone(),
+            line: source
+                .lines()
+                .nth(position.line - 1)
+                .unwrap_or_default()
+                .to_string(),
+        }
+    }
+}
+
+/// Result of `patch_buffer()`
+#[derive(Debug)]
+pub struct PatchedBuffer {
+    pub text: String,
+    pub report: FileReport,
+}
+
+/// Apply rules in `config` to `source` in order. Rules recorded in the marker of `source` are
+/// skipped.
+pub fn patch_buffer(
+    source: &str,
+    file_type: Option<FileType>,
+    config: &RuleConfig,
+) -> PatchedBuffer {
+    let applied_rules = marker::applied_rules(source);
+    let is_enabled = |rule: Rule| config.rules.contains(&rule) && !applied_rules.contains(&rule);
+    let mut text = source.to_string();
+    let mut report = FileReport::default();
+    let mut apply_rule = |text: &mut String, rule: Rule, edits: &dyn Fn(&str) -> Vec<Edit>| {
+        let edits = edits(text);
+        if !edits.is_empty() {
+            report.record(rule, text, &edits);
+            *text = edit::apply(text, &edits);
+        }
+    };
+
+    // Normalize NULL
+    if is_enabled(Rule::Null) {
+        let style = config.null_style.for_file_type(file_type);
+        apply_rule(&mut text, Rule::Null, &|source| {
+            null::null_edits(source, style, config.null_zero)
+        });
+    }
+
+    // Wipeout constexpr, consteval and constinit
+    if is_enabled(Rule::Constexpr) {
+        apply_rule(&mut text, Rule::Constexpr, &|source| {
+            constexpr::constexpr_edits(source, config.constexpr_variables)
+        });
+    }
+
+    // Escape single quotes in const char for yaml string
+    if is_enabled(Rule::SingleQuote) {
+        // Case: ... 'abc' ...
+        apply_rule(&mut text, Rule::SingleQuote, &|source| {
+            escape_single_quotes_in_const_char(source).unwrap_or_default()
+        });
+
+        // Case: ... can't ...
+        apply_rule(&mut text, Rule::SingleQuote, &|source| {
+            escape_single_quote_in_const_char(source).unwrap_or_default()
+        });
+    }
+
+    // Insert probes at the start of function bodies
+    if is_enabled(Rule::Probe) {
+        if let Some(ref probe) = config.probe {
+            apply_rule(&mut text, Rule::Probe, &|source| {
+                probe::probe_edits(source, probe)
+            });
+        }
+    }
+
+    // Record applied rules
+    if config.marker {
+        let rules = applied_rules.union(&config.rules).cloned().collect();
+        text = marker::set_applied_rules(&text, &rules);
+    }
+
+    PatchedBuffer { text, report }
+}
+
+/// Edits of all rules in `config`, each computed on `source`.
+/// Edits overlapping preceding ones are dropped.
+pub fn all_rule_edits(
+    source: &str,
+    file_type: Option<FileType>,
+    config: &RuleConfig,
+) -> Vec<(Rule, Edit)> {
+    let mut edits: Vec<(Rule, Edit)> = Vec::new();
+    if config.rules.contains(&Rule::Null) {
+        let style = config.null_style.for_file_type(file_type);
+        for edit in null::null_edits(source, style, config.null_zero) {
+            edits.push((Rule::Null, edit));
+        }
+    }
+    if config.rules.contains(&Rule::Constexpr) {
+        for edit in constexpr::constexpr_edits(source, config.constexpr_variables) {
+            edits.push((Rule::Constexpr, edit));
+        }
+    }
+    if config.rules.contains(&Rule::SingleQuote) {
+        for edit in escape_single_quotes_in_const_char(source)
+            .unwrap_or_default()
+            .into_iter()
+            .chain(escape_single_quote_in_const_char(source).unwrap_or_default())
+        {
+            edits.push((Rule::SingleQuote, edit));
+        }
+    }
+    if let (true, Some(probe)) = (config.rules.contains(&Rule::Probe), &config.probe) {
+        for edit in probe::probe_edits(source, probe) {
+            edits.push((Rule::Probe, edit));
+        }
+    }
+    edits.sort_by_key(|(_, edit)| (edit.range.start, edit.range.end));
+    let mut end = 0;
+    edits.retain(|(_, edit)| {
+        let overlapped = edit.range.start < end;
+        end = end.max(edit.range.end);
+        !overlapped
+    });
+    edits
+}
+
+/// Edits replacing matches of `re`. Returns `None` if `filter` rejects the first match.
+fn regex_edits<R: Replacer>(
+    re: &Regex,
+    original: &str,
+    mut change_to: R,
+    filter: fn(Option<Captures>) -> bool,
+) -> Option<Vec<Edit>> {
+    if filter(re.captures(original)) {
+        return None;
+    }
+    let edits = re
+        .captures_iter(original)
+        .filter_map(|caps| {
+            let matched = caps.get(0).unwrap();
+            let mut replacement = String::new();
+            change_to.replace_append(&caps, &mut replacement);
+            (replacement != matched.as_str()).then(|| Edit {
+                range: matched.range(),
+                replacement,
+            })
+        })
+        .collect();
+    Some(edits)
+}
+
+fn no_check(caps: Option<Captures>) -> bool {
+    if caps.is_none() {
+        return true;
+    }
+    false
+}
+
+fn double_quote_exists(caps: Option<Captures>) -> bool {
+    if let Some(caps) = caps {
+        caps.get(1).unwrap().as_str().contains("\"") || caps.get(3).unwrap().as_str().contains("\"")
+    } else {
+        true
+    }
+}
+
+fn escape_single_quote_in_const_char(original: &str) -> Option<Vec<Edit>> {
+    let re = Regex::new("\"(.*)n't (.*)\"").unwrap();
+    regex_edits(&re, original, "\"${1}n''t ${2}\"", no_check)
+}
+
+fn escape_single_quotes_in_const_char(original: &str) -> Option<Vec<Edit>> {
+    let re = Regex::new("\"(.*?)\\\\?'([^\"\n]{2,}?)\\\\?'(.*?)\"").unwrap();
+    regex_edits(
+        &re,
+        original,
+        |caps: &Captures| {
+            // Keep quotes already escaped (e.g. `''ab''`) as is
+            if caps[1].ends_with('\'')
+                || caps[2].starts_with('\'')
+                || caps[2].ends_with('\'')
+                || caps[3].starts_with('\'')
+            {
+                return caps[0].to_string();
+            }
+            format!("\"{}''{}''{}\"", &caps[1], &caps[2], &caps[3])
+        },
+        double_quote_exists,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    macro_rules! test_case {
+        ($func:expr, $original:expr, $patched:expr) => {
+            assert_eq!(
+                $func($original).map(|edits| edit::apply($original, &edits)),
+                $patched
+            );
+        };
+    }
+
+    #[test]
+    fn patch_buffer() {
+        let config = RuleConfig {
+            marker: true,
+            ..Default::default()
+        };
+        let patched = super::patch_buffer(
+            "constexpr int f() { return 0; }\nvoid *p = NULL;\n",
+            None,
+            &config,
+        );
+        assert_eq!(
+            patched.text,
+            "inline int f() { return 0; }\nvoid *p = (NULL);\n/* special-patch: applied=null,constexpr,single-quote */\n"
+        );
+        assert_eq!(patched.report.rules["null"].count, 1);
+        assert_eq!(patched.report.rules["constexpr"].count, 1);
+
+        let again = super::patch_buffer(&patched.text, None, &config);
+        assert_eq!(again.text, patched.text);
+        assert!(again.report.rules.is_empty());
+    }
+
+    #[test]
+    fn single_quote_should_escape() {
+        test_case!(
+            escape_single_quotes_in_const_char,
+            "let test = \"test 'ab'.\"",
+            Some(String::from("let test = \"test ''ab''.\""))
+        );
+
+        test_case!(
+            escape_single_quote_in_const_char,
+            "\"Can't stop typing.\"",
+            Some(String::from("\"Can''t stop typing.\""))
+        );
+    }
+
+    #[test]
+    fn single_quote_should_not_escape() {
+        test_case!(
+            escape_single_quotes_in_const_char,
+            "{\"text\", OPT_TEXT, '-', \"Print as text\"}",
+            None
+        );
+        test_case!(
+            escape_single_quotes_in_const_char,
+            "{\"select\", OPT_SELECT_NAME, 's', \"Select a single algorithm\"},",
+            None
+        );
+        test_case!(escape_single_quotes_in_const_char, "{ OPT_SECTION_STR, 1, '-', \"Random state\" \" options:\n\" }, {\"rand\", OPT_R_RAND, 's', \"Load the given file(s) into the random number generator\"}, {\"writerand\", OPT_R_WRITERAND, '>', \"Write random data to the specified file\"}", None);
+
+        test_case!(escape_single_quotes_in_const_char, "\"\n'abc'\"", None);
+        test_case!(escape_single_quotes_in_const_char, "\"'ab\nc'\"", None);
+
+        test_case!(escape_single_quotes_in_const_char, "\"'ab\nc'\"", None);
+    }
+
+    #[test]
+    fn single_quote_escape_should_be_idempotent() {
+        test_case!(
+            escape_single_quotes_in_const_char,
+            "let test = \"test ''ab''.\"",
+            Some(String::from("let test = \"test ''ab''.\""))
+        );
+        test_case!(
+            escape_single_quote_in_const_char,
+            "\"Can''t stop typing.\"",
+            None
+        );
+    }
+}
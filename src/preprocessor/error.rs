@@ -3,6 +3,7 @@ use std::str::FromStr;
 use regex::Error as RegexError;
 
 #[derive(Debug)]
+#[allow(dead_code, clippy::enum_variant_names)]
 pub enum Error {
     RegexError(RegexError),
     UsizeParseError(<usize as FromStr>::Err),
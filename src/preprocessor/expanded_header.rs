@@ -3,9 +3,10 @@ use super::path_like::PathLike;
 use super::result::Result;
 
 use regex::Regex;
+use serde::Serialize;
 use std::path::{Path, PathBuf};
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
 pub struct ExpandedHeader {
     line_no: usize,
     path: PathBuf,
@@ -35,7 +36,7 @@ impl ExpandedHeader {
 
     pub fn parse_line(line: &str) -> Result<Self> {
         let format = Regex::new("^# (\\d+) \"(.*)\"")?;
-        return match format.captures(line) {
+        match format.captures(line) {
             Some(matches) => match (matches.get(1), matches.get(2)) {
                 (Some(line_no), Some(path)) => match line_no.as_str().parse::<usize>() {
                     Ok(line_no) => Ok(ExpandedHeader::new(line_no, path.as_str())),
@@ -44,10 +45,21 @@ impl ExpandedHeader {
                 _ => Err(Error::LineFormatError(line.to_string())),
             },
             None => Err(Error::LineFormatError(line.to_string())),
-        };
+        }
     }
 }
 
+/// Flags following the file name of a line marker (e.g. `[1, 3, 4]` for `# 1 "stdio.h" 1 3 4`).
+/// `1` and `2` mark entering and returning to a file, and `3` marks a system header.
+pub fn line_marker_flags(line: &str) -> Vec<usize> {
+    line.rsplit('"')
+        .next()
+        .unwrap_or("")
+        .split_whitespace()
+        .filter_map(|v| v.parse().ok())
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::ExpandedHeader;
@@ -65,11 +77,20 @@ mod tests {
     }
 
     #[test]
-    fn is_system() {
+    fn line_marker_flags() {
+        assert_eq!(
+            super::line_marker_flags("# 1 \"/usr/include/stdio.h\" 1 3 4"),
+            vec![1, 3, 4]
+        );
         assert_eq!(
-            ExpandedHeader::new(1, "/usr/include/stdio.h").is_system(),
-            true
+            super::line_marker_flags("# 2 \"main.c\""),
+            Vec::<usize>::new()
         );
-        assert_eq!(ExpandedHeader::new(0, "bad.c").is_system(), false);
+    }
+
+    #[test]
+    fn is_system() {
+        assert!(ExpandedHeader::new(1, "/usr/include/stdio.h").is_system());
+        assert!(!ExpandedHeader::new(0, "bad.c").is_system());
     }
 }
@@ -0,0 +1,273 @@
+use super::error::Error;
+use super::expanded_header::{line_marker_flags, ExpandedHeader};
+use super::path_like::PathLike;
+use super::result::Result;
+
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FileNode {
+    /// Whether the file is a system header
+    pub system: bool,
+    /// Lines contributed to the preprocessed sources, excluding line markers
+    pub lines: usize,
+    /// Number of translation units including the file
+    pub translation_units: usize,
+}
+
+/// Include tree recorded in line markers of preprocessed sources.
+/// Files included implicitly (e.g. by `-include`) are included by the main file.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct IncludeGraph {
+    /// Main files of translation units
+    pub roots: BTreeSet<PathBuf>,
+    pub files: BTreeMap<PathBuf, FileNode>,
+    /// Files directly included by each file
+    pub includes: BTreeMap<PathBuf, BTreeSet<PathBuf>>,
+}
+
+/// Pseudo files such as `<built-in>` and `<command-line>`
+fn is_pseudo(path: &Path) -> bool {
+    path.to_string_lossy().starts_with('<')
+}
+
+/// Escape `"` and `\` for DOT
+fn dot_escape(path: &Path) -> String {
+    path.to_string_lossy()
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+}
+
+/// Escape spaces and `$` for Makefiles
+fn make_escape(path: &Path) -> String {
+    path.to_string_lossy()
+        .replace(' ', "\\ ")
+        .replace('$', "$$")
+}
+
+impl IncludeGraph {
+    /// Parse output of `-E` of a translation unit. Paths are as written in line markers.
+    pub fn parse(source: &str) -> Result<Self> {
+        let mut graph = IncludeGraph::default();
+        let mut root: Option<PathBuf> = None;
+        let mut stack: Vec<PathBuf> = Vec::new();
+        for line in source.lines() {
+            let marker = match ExpandedHeader::parse_line(line) {
+                Ok(marker) => marker,
+                Err(Error::LineFormatError(_)) => {
+                    if let Some(node) = stack.last().and_then(|v| graph.files.get_mut(v)) {
+                        node.lines += 1;
+                    }
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+            let path = marker.path().to_path_buf();
+            let flags = line_marker_flags(line);
+            if flags.contains(&1) {
+                // Entering an included file
+                let parent = stack
+                    .iter()
+                    .rev()
+                    .find(|v| !is_pseudo(v))
+                    .or(root.as_ref())
+                    .cloned();
+                if let Some(parent) = parent {
+                    if !is_pseudo(&path) {
+                        graph
+                            .includes
+                            .entry(parent)
+                            .or_default()
+                            .insert(path.clone());
+                    }
+                }
+                stack.push(path.clone());
+            } else if flags.contains(&2) {
+                // Returning to an including file
+                while stack.last().is_some_and(|v| v != &path) {
+                    stack.pop();
+                }
+                if stack.is_empty() {
+                    stack.push(path.clone());
+                }
+            } else {
+                // Switching to another file (e.g. from `<command-line>` to the main file)
+                match stack.last_mut() {
+                    Some(top) => *top = path.clone(),
+                    None => stack.push(path.clone()),
+                }
+            }
+
+            if is_pseudo(&path) {
+                continue;
+            }
+            if root.is_none() {
+                root = Some(path.clone());
+                graph.roots.insert(path.clone());
+            }
+            let system = marker.is_system() || flags.contains(&3);
+            let node = graph.files.entry(path).or_insert(FileNode {
+                system,
+                lines: 0,
+                translation_units: 1,
+            });
+            node.system |= system;
+        }
+        Ok(graph)
+    }
+
+    /// Replace paths with ones returned by `resolve`
+    pub fn map_paths(self, resolve: impl Fn(&Path) -> PathBuf) -> Self {
+        IncludeGraph {
+            roots: self.roots.iter().map(|v| resolve(v)).collect(),
+            files: self
+                .files
+                .into_iter()
+                .map(|(path, node)| (resolve(&path), node))
+                .collect(),
+            includes: self
+                .includes
+                .into_iter()
+                .map(|(path, included)| {
+                    (
+                        resolve(&path),
+                        included.iter().map(|v| resolve(v)).collect(),
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    /// Drop system headers
+    pub fn project_only(mut self) -> Self {
+        let system: BTreeSet<PathBuf> = self
+            .files
+            .iter()
+            .filter(|(_, node)| node.system)
+            .map(|(path, _)| path.clone())
+            .collect();
+        self.files.retain(|path, _| !system.contains(path));
+        self.includes.retain(|path, _| !system.contains(path));
+        for included in self.includes.values_mut() {
+            included.retain(|v| !system.contains(v));
+        }
+        self.includes.retain(|_, included| !included.is_empty());
+        self
+    }
+
+    /// Merge graph of another translation unit. Lines and translation units are summed up.
+    pub fn merge(&mut self, other: IncludeGraph) {
+        self.roots.extend(other.roots);
+        for (path, node) in other.files.into_iter() {
+            match self.files.get_mut(&path) {
+                Some(v) => {
+                    v.system |= node.system;
+                    v.lines += node.lines;
+                    v.translation_units += node.translation_units;
+                }
+                None => {
+                    self.files.insert(path, node);
+                }
+            }
+        }
+        for (path, included) in other.includes.into_iter() {
+            self.includes.entry(path).or_default().extend(included);
+        }
+    }
+
+    /// Graphviz DOT. Main files are boxes and system headers are dashed.
+    pub fn to_dot(&self) -> String {
+        let mut result = String::from("digraph includes {\n");
+        for (path, node) in self.files.iter() {
+            let mut attributes = vec![format!(
+                "label=\"{}\\n{} lines\"",
+                dot_escape(path),
+                node.lines
+            )];
+            if self.roots.contains(path) {
+                attributes.push(String::from("shape=box"));
+            }
+            if node.system {
+                attributes.push(String::from("style=dashed"));
+            }
+            result.push_str(&format!(
+                "    \"{}\" [{}];\n",
+                dot_escape(path),
+                attributes.join(", ")
+            ));
+        }
+        for (path, included) in self.includes.iter() {
+            for v in included.iter() {
+                result.push_str(&format!(
+                    "    \"{}\" -> \"{}\";\n",
+                    dot_escape(path),
+                    dot_escape(v)
+                ));
+            }
+        }
+        result.push_str("}\n");
+        result
+    }
+
+    /// Makefile rule making `target` depend on all files in the graph, as `-MD` of gcc does
+    pub fn to_depfile(&self, target: &Path) -> String {
+        let prerequisites: Vec<String> = self
+            .roots
+            .iter()
+            .chain(self.files.keys().filter(|v| !self.roots.contains(*v)))
+            .map(|v| make_escape(v))
+            .collect();
+        let mut result = format!("{}:", make_escape(target));
+        for v in prerequisites.iter() {
+            result.push_str(&format!(" \\\n  {}", v));
+        }
+        result.push('\n');
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let source = std::fs::read_to_string("./test/preprocessor/main.E").unwrap();
+        let graph = IncludeGraph::parse(&source).unwrap();
+        assert_eq!(graph.roots, BTreeSet::from([PathBuf::from("main.c")]));
+        assert_eq!(
+            graph.files[Path::new("calc.h")],
+            FileNode {
+                system: false,
+                lines: 9,
+                translation_units: 1,
+            }
+        );
+        assert!(graph.files[Path::new("/usr/include/stdio.h")].system);
+        assert_eq!(
+            graph.includes[Path::new("main.c")],
+            BTreeSet::from([
+                PathBuf::from("/usr/include/stdc-predef.h"),
+                PathBuf::from("/usr/include/stdio.h"),
+                PathBuf::from("calc.h"),
+            ])
+        );
+        assert!(graph.includes[Path::new("/usr/include/stdio.h")]
+            .contains(Path::new("/usr/include/x86_64-linux-gnu/bits/floatn.h")));
+
+        let project = graph.clone().project_only();
+        assert_eq!(project.files.len(), 2);
+        assert_eq!(
+            project.to_depfile(Path::new("main.o")),
+            "main.o: \\\n  main.c \\\n  calc.h\n"
+        );
+        assert!(project.to_dot().contains("    \"main.c\" -> \"calc.h\";\n"));
+
+        let mut merged = graph.clone();
+        merged.merge(graph);
+        assert_eq!(merged.files[Path::new("calc.h")].lines, 18);
+        assert_eq!(merged.files[Path::new("calc.h")].translation_units, 2);
+    }
+}
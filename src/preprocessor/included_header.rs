@@ -3,9 +3,10 @@ use super::path_like::PathLike;
 use super::result::Result;
 
 use regex::Regex;
+use serde::Serialize;
 use std::path::{Path, PathBuf};
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
 pub struct IncludedHeader {
     path: PathBuf,
 }
@@ -25,13 +26,13 @@ impl IncludedHeader {
 
     pub fn parse_line(line: &str) -> Result<Self> {
         let format = Regex::new("^#\\s*include\\s*[\"<](.*)[\">]")?;
-        return match format.captures(line) {
+        match format.captures(line) {
             Some(matches) => match matches.get(1) {
                 Some(path) => Ok(IncludedHeader::new(path.as_str())),
                 None => Err(Error::LineFormatError(line.to_string())),
             },
             None => Err(Error::LineFormatError(line.to_string())),
-        };
+        }
     }
 }
 
@@ -71,10 +72,7 @@ mod tests {
 
     #[test]
     fn is_system() {
-        assert_eq!(
-            IncludedHeader::new("/usr/include/stdio.h").is_system(),
-            true
-        );
-        assert_eq!(IncludedHeader::new("bad.c").is_system(), false);
+        assert!(IncludedHeader::new("/usr/include/stdio.h").is_system());
+        assert!(!IncludedHeader::new("bad.c").is_system());
     }
 }
@@ -1,30 +1,135 @@
 pub mod error;
 pub mod expanded_header;
+pub mod include_graph;
 pub mod included_header;
 pub mod path_like;
 pub mod result;
 
+use clap::ValueEnum;
+use serde::Serialize;
 use std::{
-    collections::{HashMap, HashSet},
-    path::PathBuf,
+    collections::HashSet,
+    path::{Path, PathBuf},
 };
 
 use error::Error;
-use expanded_header::ExpandedHeader;
+use expanded_header::{line_marker_flags, ExpandedHeader};
 use included_header::IncludedHeader;
 use path_like::PathLike;
 use result::Result;
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", content = "value", rename_all = "kebab-case")]
 pub enum Line {
     IncludedHeader(IncludedHeader),
     ExpandedHeader(ExpandedHeader),
     Other(String),
 }
 
-enum SourceContext {
-    ExpandedHeader(ExpandedHeader),
-    Main,
+impl Line {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Line::IncludedHeader(_) => "included-header",
+            Line::ExpandedHeader(_) => "expanded-header",
+            Line::Other(_) => "other",
+        }
+    }
+}
+
+/// Line of preprocessed source with the decision of `Preprocessor::preprocess()`
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct AnnotatedLine {
+    /// 1-based line number in the preprocessed source
+    pub number: usize,
+    pub text: String,
+    pub line: Line,
+    /// Line marker of the file which the line belongs to. `None` before the first line marker
+    pub context: Option<ExpandedHeader>,
+    /// Line number in the file of `context`, counted from the last line marker
+    pub source_line: Option<usize>,
+    /// Whether the line belongs to a system header
+    pub system: bool,
+    /// Whether the line belongs to a header forced by `-include` or `-imacros`, or is the
+    /// directive of one
+    pub forced_include: bool,
+    /// `#include` directive of a forced header printed before the line, with its path resolved
+    /// against `ForcedIncludes::directory`
+    pub restored_include: Option<String>,
+    /// Whether `Preprocessor::preprocess()` keeps the line
+    pub keep: bool,
+}
+
+fn is_pseudo_file(path: &Path) -> bool {
+    path.to_string_lossy().starts_with('<')
+}
+
+/// How `Preprocessor::preprocess()` handles headers forced by `-include`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ForcedIncludeMode {
+    /// Replace content with one `#include` directive, expecting `-include` to be removed from
+    /// the compile command
+    Directive,
+    /// Drop content, expecting `-include` to be kept in the compile command
+    #[default]
+    Strip,
+    /// Keep content, expecting `-include` to be removed from the compile command
+    Inline,
+}
+
+/// Headers forced by `-include` and `-imacros` and how to handle them
+#[derive(Debug, Clone, Default)]
+pub struct ForcedIncludes {
+    pub mode: ForcedIncludeMode,
+    /// Headers given by `-include`. If neither they nor `imacros` are given, non-system headers
+    /// entered from `<command-line>` are taken as forced.
+    pub includes: Vec<PathBuf>,
+    /// Headers given by `-imacros`. They print no content and are never restored as `#include`.
+    pub imacros: Vec<PathBuf>,
+    /// Directory which relative paths of forced headers are resolved against, i.e. that of
+    /// the compile command
+    pub directory: PathBuf,
+}
+
+fn matches_any(paths: &[PathBuf], path: &Path) -> bool {
+    let path = path.strip_prefix("./").unwrap_or(path);
+    paths.iter().any(|v| {
+        let v = v.strip_prefix("./").unwrap_or(v);
+        path.ends_with(v) || v.ends_with(path)
+    })
+}
+
+impl ForcedIncludes {
+    fn is_imacros(&self, path: &Path) -> bool {
+        matches_any(&self.imacros, path)
+    }
+
+    /// Whether the header entered from `<command-line>` is forced by the compile command.
+    /// Headers the preprocessor forces by itself (e.g. `stdc-predef.h`) are not.
+    fn is_forced(&self, path: &Path, system: bool) -> bool {
+        if self.includes.is_empty() && self.imacros.is_empty() {
+            return !system;
+        }
+        matches_any(&self.includes, path) || self.is_imacros(path)
+    }
+
+    /// `#include` directive of a forced header, so that it is found regardless of the
+    /// directory the patched source is compiled in
+    fn directive(&self, path: &Path) -> String {
+        let path = self.directory.join(path.strip_prefix("./").unwrap_or(path));
+        let path = path.canonicalize().unwrap_or(path);
+        format!("#include \"{}\"", path.display())
+    }
+}
+
+/// Statistics of `Preprocessor::preprocess_with_stats()`
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct PreprocessStats {
+    /// Project headers whose content is inlined
+    pub headers_inlined: usize,
+    /// `#include` directives kept in place of system headers
+    pub includes_restored: usize,
+    /// `#include` directives dropped since the header is inlined or included by system headers
+    pub includes_dropped: usize,
 }
 
 #[derive(Debug)]
@@ -32,61 +137,187 @@ pub struct Preprocessor {}
 
 impl Preprocessor {
     pub fn preprocess(source: &str) -> Result<String> {
-        let mut current_source_context = SourceContext::Main;
-        let mut markers: HashMap<usize, ExpandedHeader> = HashMap::new();
+        Self::preprocess_with_stats(source, &ForcedIncludes::default()).map(|(result, _)| result)
+    }
+
+    pub fn preprocess_with_stats(
+        source: &str,
+        forced_includes: &ForcedIncludes,
+    ) -> Result<(String, PreprocessStats)> {
+        let mut stats = PreprocessStats::default();
+        let mut main_file = None;
         let mut expands: HashSet<PathBuf> = HashSet::new();
-        for line in source.lines() {
-            match Self::parse_line(line)? {
+        let mut result = String::new();
+        for line in Self::annotate(source, forced_includes)? {
+            match line.line {
                 Line::ExpandedHeader(ref header) => {
-                    current_source_context = SourceContext::ExpandedHeader(header.clone());
                     expands.insert(header.path().to_path_buf());
+                    main_file.get_or_insert_with(|| header.path().to_path_buf());
                 }
-                Line::IncludedHeader(_) => {
-                    if let SourceContext::ExpandedHeader(ref marker) = current_source_context {
-                        markers.insert(*marker.line_no(), marker.clone());
-                    }
+                Line::IncludedHeader(_) if line.keep => stats.includes_restored += 1,
+                Line::IncludedHeader(_) if line.restored_include.is_none() => {
+                    stats.includes_dropped += 1
                 }
-                _ => (),
+                Line::IncludedHeader(_) => (),
+                Line::Other(_) => (),
+            }
+            if let Some(ref directive) = line.restored_include {
+                stats.includes_restored += 1;
+                result = result + directive + "\n";
+            }
+            if line.keep {
+                result = result + &line.text + "\n";
             }
         }
+        stats.headers_inlined = expands
+            .iter()
+            .filter(|v| !v.is_system() && !is_pseudo_file(v))
+            .filter(|v| Some(*v) != main_file.as_ref())
+            .count();
+        Ok((result, stats))
+    }
 
-        let mut current_source_context = SourceContext::Main;
-        let mut result = String::new();
-        'outer: for line in source.lines() {
-            match Self::parse_line(line)? {
+    /// Classify each line of preprocessed source and decide whether `preprocess()` keeps it.
+    /// Headers entered from `<built-in>` or `<command-line>` (i.e. forced by `-include`) are
+    /// handled by `forced_includes.mode`.
+    pub fn annotate(source: &str, forced_includes: &ForcedIncludes) -> Result<Vec<AnnotatedLine>> {
+        let restore = forced_includes.mode == ForcedIncludeMode::Directive;
+        let inline = forced_includes.mode == ForcedIncludeMode::Inline;
+        let mut expands: HashSet<PathBuf> = HashSet::new();
+        for line in source.lines() {
+            if let Line::ExpandedHeader(ref header) = Self::parse_line(line)? {
+                expands.insert(header.path().to_path_buf());
+            }
+        }
+
+        let mut result = Vec::new();
+        let mut context: Option<ExpandedHeader> = None;
+        let mut next_source_line: Option<usize> = None;
+        // Forced header being expanded
+        let mut forced: Option<PathBuf> = None;
+        // Forced headers whose `#include` directive is kept
+        let mut restored: Vec<PathBuf> = Vec::new();
+        for (index, text) in source.lines().enumerate() {
+            let line = Self::parse_line(text)?;
+            let in_system = context.as_ref().is_some_and(|v| v.is_system());
+            let in_pseudo_file = context.as_ref().is_some_and(|v| is_pseudo_file(v.path()));
+            let mut restored_include = None;
+            let mut forced_directive = false;
+            let (system, keep, source_line) = match line {
                 Line::ExpandedHeader(ref header) => {
-                    current_source_context = SourceContext::ExpandedHeader(header.clone());
-                    // `error: invalid line marker flag '2': cannot pop empty include stack` を避けるため、出力はする
-                    // if header.is_system() {
-                    //     continue;
-                    // }
-                }
-                Line::IncludedHeader(ref header) => {
-                    if let SourceContext::ExpandedHeader(ref last_expand) = current_source_context {
-                        if last_expand.is_system() {
-                            continue;
+                    if is_pseudo_file(header.path()) {
+                        forced = None;
+                    } else if in_pseudo_file
+                        && line_marker_flags(text).contains(&1)
+                        && forced_includes.is_forced(
+                            header.path(),
+                            header.is_system() || line_marker_flags(text).contains(&3),
+                        )
+                    {
+                        let path = header.path().to_path_buf();
+                        // Content of system headers is never kept, so they are restored even
+                        // when inlining
+                        if (restore || (inline && header.is_system()))
+                            && !forced_includes.is_imacros(&path)
+                            && !restored.iter().any(|v| v.ends_with(&path))
+                        {
+                            restored_include = Some(forced_includes.directive(&path));
+                            restored.push(path.clone());
                         }
+                        forced = Some(path);
                     }
-                    for path in expands.iter() {
-                        // Ignore include statement that already expanded
-                        if !path.is_system() && path.ends_with(header.path()) {
-                            continue 'outer;
-                        }
+                    context = Some(header.clone());
+                    next_source_line = Some(*header.line_no());
+                    // `error: invalid line marker flag '2': cannot pop empty include stack` を避けるため、出力はする
+                    (header.is_system(), true, None)
+                }
+                Line::IncludedHeader(ref header) if in_pseudo_file && !in_system => {
+                    // Directive of a forced header printed by Clang
+                    let path = header.path().to_path_buf();
+                    if restore
+                        && !forced_includes.is_imacros(&path)
+                        && !restored.iter().any(|v| v.ends_with(&path))
+                    {
+                        restored_include = Some(forced_includes.directive(&path));
+                        restored.push(path);
                     }
+                    forced_directive = true;
+                    (false, false, None)
+                }
+                Line::IncludedHeader(ref header) => {
+                    // Ignore include statement that already expanded
+                    let expanded = expands
+                        .iter()
+                        .any(|path| !path.is_system() && path.ends_with(header.path()));
+                    (
+                        in_system,
+                        !in_system && !expanded && (forced.is_none() || inline),
+                        None,
+                    )
+                }
+                // Pseudo directive of `-imacros` printed by Clang
+                Line::Other(_) if in_pseudo_file && text.starts_with("#__include_macros") => {
+                    forced_directive = true;
+                    (false, false, None)
                 }
                 Line::Other(_) => {
-                    if let SourceContext::ExpandedHeader(ref header) = current_source_context {
-                        if header.is_system() {
-                            continue;
-                        }
-                    }
+                    let source_line = next_source_line;
+                    next_source_line = next_source_line.map(|v| v + 1);
+                    (
+                        in_system,
+                        !in_system && (forced.is_none() || inline),
+                        source_line,
+                    )
                 }
-            }
-            result = result + line + "\n";
+            };
+            result.push(AnnotatedLine {
+                number: index + 1,
+                text: text.to_string(),
+                forced_include: forced.is_some() || forced_directive,
+                line,
+                context: context.clone(),
+                source_line,
+                system,
+                restored_include,
+                keep,
+            });
         }
         Ok(result)
     }
 
+    /// Non-system headers expanded in the preprocessed source, as written in line markers
+    pub fn project_headers(source: &str) -> Result<HashSet<PathBuf>> {
+        let mut headers = HashSet::new();
+        for line in source.lines() {
+            if let Line::ExpandedHeader(ref header) = Self::parse_line(line)? {
+                // Flag `3` marks system headers (e.g. ones in `-isystem` directories)
+                if header.is_system() || line_marker_flags(line).contains(&3) {
+                    continue;
+                }
+                // Skip pseudo files such as `<built-in>` and `<command-line>`
+                if is_pseudo_file(header.path()) {
+                    continue;
+                }
+                headers.insert(header.path().to_path_buf());
+            }
+        }
+        Ok(headers)
+    }
+
+    /// System headers expanded in the preprocessed source, whose content `preprocess()` replaces
+    /// with `#include` directives
+    pub fn system_headers(source: &str) -> Result<HashSet<PathBuf>> {
+        let mut headers = HashSet::new();
+        for line in source.lines() {
+            if let Line::ExpandedHeader(ref header) = Self::parse_line(line)? {
+                if header.is_system() {
+                    headers.insert(header.path().to_path_buf());
+                }
+            }
+        }
+        Ok(headers)
+    }
+
     fn parse_line(line: &str) -> Result<Line> {
         {
             let result = IncludedHeader::parse_line(line);
@@ -108,7 +339,7 @@ impl Preprocessor {
                 },
             }
         }
-        return Ok(Line::Other(line.to_string()));
+        Ok(Line::Other(line.to_string()))
     }
 }
 
@@ -152,15 +383,180 @@ mod tests {
         assert!(result == exptected);
     }
 
-    // #[test]
-    // fn debug() {
-    //     let source = std::fs::read_to_string("./test/preprocessor/pngrtran.c").unwrap();
-    //     for line in source.lines() {
-    //         println!("{:?}", Preprocessor::parse_line(line));
-    //     }
-    //     println!("{}", Preprocessor::preprocess(&source).unwrap());
-    //     assert!(false)
-    // }
+    #[test]
+    fn preprocess_with_stats() {
+        let source = std::fs::read_to_string("./test/preprocessor/main.E").unwrap();
+        let (_, stats) =
+            Preprocessor::preprocess_with_stats(&source, &ForcedIncludes::default()).unwrap();
+        assert_eq!(
+            stats,
+            PreprocessStats {
+                headers_inlined: 1,
+                includes_restored: 1,
+                includes_dropped: 39,
+            }
+        );
+    }
+
+    #[test]
+    fn project_headers() {
+        let source = std::fs::read_to_string("./test/preprocessor/main.E").unwrap();
+        let headers = Preprocessor::project_headers(&source).unwrap();
+        assert_eq!(
+            headers,
+            HashSet::from([PathBuf::from("main.c"), PathBuf::from("calc.h")])
+        );
+    }
+
+    #[test]
+    fn annotate() {
+        let source = std::fs::read_to_string("./test/preprocessor/main.E").unwrap();
+        let lines = Preprocessor::annotate(&source, &ForcedIncludes::default()).unwrap();
+        assert_eq!(lines.len(), source.lines().count());
+
+        // `#include <stdio.h>` in main.c is kept in place of the system header
+        let stdio = lines
+            .iter()
+            .find(|v| v.line == Line::IncludedHeader(IncludedHeader::new("stdio.h")))
+            .unwrap();
+        assert!(stdio.keep);
+        assert!(!stdio.system);
+        assert_eq!(stdio.context, Some(ExpandedHeader::new(1, "main.c")));
+
+        // Inlined project header
+        let calc = &lines[2462];
+        assert_eq!(
+            calc.line,
+            Line::IncludedHeader(IncludedHeader::new("calc.h"))
+        );
+        assert!(!calc.keep);
+
+        // Content of system headers is dropped, and that of project headers is kept
+        let content = &lines[2465];
+        assert_eq!(content.context, Some(ExpandedHeader::new(1, "calc.h")));
+        assert_eq!(content.source_line, Some(1));
+        assert!(content.keep);
+        assert!(lines
+            .iter()
+            .filter(|v| matches!(v.line, Line::Other(_)) && v.system)
+            .all(|v| !v.keep));
+    }
+
+    #[test]
+    fn forced_include() {
+        // GCC does not print directives of headers forced by `-include`
+        let source = r#"# 0 "u.c"
+# 0 "<built-in>"
+# 0 "<command-line>"
+# 1 "/usr/include/stdc-predef.h" 1 3 4
+# 0 "<command-line>" 2
+# 1 "./forced.h" 1
+#include <stdio.h>
+void log_(int);
+# 0 "<command-line>" 2
+# 1 "u.c"
+void f(void) { LOG(1); }
+"#;
+        let forced_includes = ForcedIncludes {
+            mode: ForcedIncludeMode::Directive,
+            directory: PathBuf::from("/src"),
+            ..Default::default()
+        };
+        let (result, stats) =
+            Preprocessor::preprocess_with_stats(source, &forced_includes).unwrap();
+        assert!(result.contains(
+            "# 0 \"<command-line>\" 2\n#include \"/src/forced.h\"\n# 1 \"./forced.h\" 1\n# 0 \"<command-line>\" 2\n"
+        ));
+        assert!(!result.contains("void log_"));
+        assert!(!result.contains("#include <stdio.h>"));
+        assert!(result.contains("void f(void) { LOG(1); }"));
+        assert_eq!(stats.includes_restored, 1);
+
+        let preprocess = |mode, imacros: &[&str]| {
+            let forced_includes = ForcedIncludes {
+                mode,
+                imacros: imacros.iter().map(PathBuf::from).collect(),
+                directory: PathBuf::from("/src"),
+                ..Default::default()
+            };
+            Preprocessor::preprocess_with_stats(source, &forced_includes)
+                .unwrap()
+                .0
+        };
+        let result = preprocess(ForcedIncludeMode::Strip, &[]);
+        assert!(!result.contains("#include \"/src/forced.h\""));
+        assert!(!result.contains("void log_"));
+        let result = preprocess(ForcedIncludeMode::Inline, &[]);
+        assert!(!result.contains("#include \"/src/forced.h\""));
+        assert!(result.contains("#include <stdio.h>\nvoid log_(int);\n"));
+        // `-imacros` headers are not restored
+        let result = preprocess(ForcedIncludeMode::Directive, &["forced.h"]);
+        assert!(!result.contains("#include \"/src/forced.h\""));
+
+        // Clang prints them in `<built-in>`
+        let source = std::fs::read_to_string("./test/preprocessor/pngrtran.c").unwrap();
+        let forced_includes = ForcedIncludes {
+            mode: ForcedIncludeMode::Directive,
+            ..Default::default()
+        };
+        let (result, _) = Preprocessor::preprocess_with_stats(&source, &forced_includes).unwrap();
+        assert_eq!(
+            result
+                .matches("#include \"/source/try-clang/canary.h\"")
+                .count(),
+            1
+        );
+        assert!(!result.contains("void __magma_log"));
+        let result = Preprocessor::preprocess(&source).unwrap();
+        assert!(!result.contains("#include \"/source/try-clang/canary.h\""));
+        assert!(!result.contains("void __magma_log"));
+    }
+
+    #[test]
+    fn forced_system_include() {
+        // Headers in system directories (e.g. `-isystem`) are marked with flag `3`
+        let source = r#"# 0 "u.c"
+# 0 "<built-in>"
+# 0 "<command-line>"
+# 1 "/usr/include/stdc-predef.h" 1 3 4
+# 0 "<command-line>" 2
+# 1 "/opt/sdk/include/sdk_config.h" 1 3
+void sdk_config(void);
+# 0 "<command-line>" 2
+# 1 "/usr/include/config.h" 1 3 4
+void usr_config(void);
+# 0 "<command-line>" 2
+# 1 "u.c"
+void f(void) { }
+"#;
+        let preprocess = |mode| {
+            let forced_includes = ForcedIncludes {
+                mode,
+                includes: vec![PathBuf::from("sdk_config.h"), PathBuf::from("config.h")],
+                directory: PathBuf::from("/src"),
+                ..Default::default()
+            };
+            let lines = Preprocessor::annotate(source, &forced_includes).unwrap();
+            assert!(lines[5].forced_include && lines[8].forced_include);
+            // Not given by `-include`
+            assert!(!lines[3].forced_include);
+            Preprocessor::preprocess_with_stats(source, &forced_includes)
+                .unwrap()
+                .0
+        };
+        let result = preprocess(ForcedIncludeMode::Strip);
+        assert!(!result.contains("void sdk_config"));
+        assert!(!result.contains("#include"));
+        let result = preprocess(ForcedIncludeMode::Directive);
+        assert!(!result.contains("void sdk_config"));
+        assert!(result.contains("#include \"/opt/sdk/include/sdk_config.h\"\n"));
+        assert!(result.contains("#include \"/usr/include/config.h\"\n"));
+        // Content of headers in `/usr` is not inlined, so they are restored
+        let result = preprocess(ForcedIncludeMode::Inline);
+        assert!(result.contains("void sdk_config(void);\n"));
+        assert!(!result.contains("/opt/sdk/include/sdk_config.h\"\n"));
+        assert!(result.contains("#include \"/usr/include/config.h\"\n"));
+    }
 
     #[test]
     fn path() {
@@ -10,6 +10,6 @@ pub trait PathLike<'a> {
 
 impl<'a> PathLike<'a> for std::path::PathBuf {
     fn path(&'a self) -> &'a Path {
-        &self
+        self
     }
 }
@@ -0,0 +1,282 @@
+//! Insertion of probes at the start of function bodies.
+//!
+//! Function definitions are detected by tokens at file scope and in namespaces and
+//! `extern "C"` blocks. Prototypes, macro definitions and bodies of classes and structs are
+//! skipped, and K&R-style definitions are detected across their parameter declarations.
+
+use crate::edit::Edit;
+use crate::tokenizer::{self, Token, TokenKind};
+
+use regex::Regex;
+use std::path::Path;
+
+/// Snippet inserted by `Rule::Probe` and where to insert it
+#[derive(Debug, Clone)]
+pub struct Probe {
+    /// Code inserted right after `{` of function bodies, e.g. `PROBE(__func__, __FILE__, __LINE__);`
+    pub snippet: String,
+    /// Insert only into functions whose names match
+    pub function: Option<Regex>,
+    /// Insert only into files whose paths match
+    pub file: Option<Regex>,
+}
+
+impl Probe {
+    pub fn matches_file(&self, path: &Path) -> bool {
+        self.file
+            .as_ref()
+            .is_none_or(|v| v.is_match(&path.to_string_lossy()))
+    }
+}
+
+/// Function definition found by `function_definitions()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionDefinition<'a> {
+    pub name: &'a str,
+    /// Byte offset of `{` of the body
+    pub body: usize,
+}
+
+/// Keywords followed by parenthesized groups which are not parameter lists
+const GROUP_KEYWORDS: [&str; 9] = [
+    "__attribute__",
+    "__attribute",
+    "__declspec",
+    "__asm__",
+    "__asm",
+    "asm",
+    "alignas",
+    "_Alignas",
+    "decltype",
+];
+
+/// Identifiers following parameter lists of C++ functions, which are not K&R parameter
+/// declarations
+const CXX_SPECIFIERS: [&str; 7] = [
+    "const", "volatile", "noexcept", "override", "final", "throw", "requires",
+];
+
+/// Index of `(` opening the parameter list of the declarator in `tokens`, if `tokens` look
+/// like the head of a function definition
+fn parameter_list(tokens: &[Token]) -> Option<usize> {
+    let mut depth: usize = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        match token.text {
+            "(" if depth == 0 && i > 0 && !GROUP_KEYWORDS.contains(&tokens[i - 1].text) => {
+                // Parenthesized declarator such as `(*f(void))(int)`
+                if tokens.get(i + 1).is_some_and(|v| v.is("*")) {
+                    let close = tokenizer::matching_bracket(tokens, i)?;
+                    return parameter_list(&tokens[i + 1..close]).map(|v| v + i + 1);
+                }
+                return Some(i);
+            }
+            "=" if depth == 0 => return None,
+            "(" | "[" | "{" => depth += 1,
+            ")" | "]" | "}" => depth = depth.saturating_sub(1),
+            _ => (),
+        }
+    }
+    None
+}
+
+/// Whether `;` at `semicolon` ends a parameter declaration of a K&R-style definition, e.g.
+/// `int a;` in `int f(a) int a; {`. The parameter list of the head must be an identifier list,
+/// and only declarations ending in `;` may follow up to `{` of the body. Prototypes followed by
+/// macros such as `PNG_NORETURN` are not.
+fn is_knr_declaration(tokens: &[Token], statement_start: usize, semicolon: usize) -> bool {
+    let head = &tokens[statement_start..semicolon];
+    let close = match parameter_list(head)
+        .and_then(|open| Some((open, tokenizer::matching_bracket(head, open)?)))
+    {
+        Some((open, close)) => {
+            let identifiers = &head[open + 1..close];
+            let is_identifier_list = identifiers.len() % 2 == 1
+                && identifiers.iter().enumerate().all(|(i, v)| {
+                    if i % 2 == 0 {
+                        v.kind == TokenKind::Identifier
+                    } else {
+                        v.is(",")
+                    }
+                });
+            if !is_identifier_list {
+                return false;
+            }
+            close
+        }
+        None => return false,
+    };
+    let is_declaration = head.get(close + 1).is_some_and(|next| {
+        next.kind == TokenKind::Identifier
+            && !GROUP_KEYWORDS.contains(&next.text)
+            && !CXX_SPECIFIERS.contains(&next.text)
+    });
+    if !is_declaration {
+        return false;
+    }
+
+    // `{` of the body follows `;`, so that `struct s {` after the last `;` is not a body
+    let mut depth: usize = 0;
+    for (i, token) in tokens.iter().enumerate().skip(semicolon + 1) {
+        match token.text {
+            "(" | "[" => depth += 1,
+            ")" | "]" => depth = depth.saturating_sub(1),
+            "{" if depth == 0 => return tokens[i - 1].is(";"),
+            "}" | "=" if depth == 0 => return false,
+            _ => (),
+        }
+    }
+    false
+}
+
+/// Name of the function if `tokens` before `{` are the head of a function definition.
+/// Heads without return types (e.g. `TEST(a, b)`) are macro invocations.
+fn function_name<'a>(tokens: &[Token<'a>]) -> Option<&'a str> {
+    let open = parameter_list(tokens)?;
+    let name = &tokens[open - 1];
+    if name.kind != TokenKind::Identifier
+        || ["if", "for", "while", "switch", "return", "sizeof"].contains(&name.text)
+        || open == 1
+    {
+        return None;
+    }
+    Some(name.text)
+}
+
+/// Whether `{` after `tokens` opens a scope containing function definitions
+fn is_namespace(tokens: &[Token]) -> bool {
+    match tokens {
+        [] => false,
+        [first, ..] if first.is("namespace") || first.is("inline") => {
+            tokens.iter().any(|v| v.is("namespace"))
+        }
+        [first, second] => first.is("extern") && second.kind == TokenKind::String,
+        _ => false,
+    }
+}
+
+/// Function definitions in `source`
+pub fn function_definitions(source: &str) -> Vec<FunctionDefinition<'_>> {
+    let tokens: Vec<Token> = tokenizer::significant_tokens(source)
+        .into_iter()
+        .filter(|v| v.kind != TokenKind::Directive)
+        .collect();
+    let mut definitions = Vec::new();
+    let mut statement_start = 0;
+    let mut depth: usize = 0;
+    let mut i = 0;
+    while i < tokens.len() {
+        let token = &tokens[i];
+        match token.text {
+            "(" | "[" => depth += 1,
+            ")" | "]" => depth = depth.saturating_sub(1),
+            ";" if depth == 0 && !is_knr_declaration(&tokens, statement_start, i) => {
+                statement_start = i + 1;
+            }
+            // End of a namespace
+            "}" if depth == 0 => statement_start = i + 1,
+            "{" if depth == 0 => {
+                let head = &tokens[statement_start..i];
+                if is_namespace(head) {
+                    statement_start = i + 1;
+                    i += 1;
+                    continue;
+                }
+                if let Some(name) = function_name(head) {
+                    definitions.push(FunctionDefinition {
+                        name,
+                        body: token.start,
+                    });
+                }
+                // Skip bodies of functions, classes and initializers
+                match tokenizer::matching_bracket(&tokens, i) {
+                    Some(close) => {
+                        i = close;
+                        // `struct s { ... } v;` continues to `;`, while bodies of functions
+                        // and macro invocations such as `TEST(a, b) { ... }` end statements
+                        if parameter_list(head).is_some() {
+                            statement_start = close + 1;
+                        }
+                    }
+                    None => break,
+                }
+            }
+            _ => (),
+        }
+        i += 1;
+    }
+    definitions
+}
+
+/// Edits inserting `probe` right after `{` of function bodies, keeping line numbers.
+/// Bodies already starting with the snippet are skipped.
+pub fn probe_edits(source: &str, probe: &Probe) -> Vec<Edit> {
+    let snippet = probe.snippet.trim();
+    function_definitions(source)
+        .into_iter()
+        .filter(|v| probe.function.as_ref().is_none_or(|re| re.is_match(v.name)))
+        .filter(|v| !source[v.body + 1..].trim_start().starts_with(snippet))
+        .map(|v| Edit::new(v.body + 1..v.body + 1, &format!(" {}", snippet)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::edit;
+
+    #[test]
+    fn function_definitions() {
+        let source = r#"#define MAX(a, b) { return a > b ? a : b; }
+int prototype(int a);
+void png_error(const char *m) PNG_NORETURN;
+struct after_attribute { int a; };
+static int (*handler)(int) = 0;
+struct s { int (*f)(void); } v;
+int table[] = { 1, 2 };
+static inline int add(int a, int b) {
+    if (a) { return a + b; }
+    return b;
+}
+int knr(a, b)
+    int a;
+    char *b;
+{
+    return a;
+}
+int (*get(void))(int) { return 0; }
+TEST(suite, name) { }
+__attribute__((noinline)) void attributed(void) __attribute__((cold)) { }
+extern "C" {
+void c_linkage(void) { }
+}
+namespace ns {
+int A::method() const noexcept { return 0; }
+}
+"#;
+        let names: Vec<&str> = super::function_definitions(source)
+            .iter()
+            .map(|v| v.name)
+            .collect();
+        assert_eq!(
+            names,
+            vec!["add", "knr", "get", "attributed", "c_linkage", "method"]
+        );
+    }
+
+    #[test]
+    fn probe_edits() {
+        let probe = Probe {
+            snippet: String::from("PROBE(__func__);"),
+            function: Some(Regex::new("^png_").unwrap()),
+            file: None,
+        };
+        let source = "void png_read(void) {\n    work();\n}\nvoid other(void) {}\n";
+        let patched = edit::apply(source, &super::probe_edits(source, &probe));
+        assert_eq!(
+            patched,
+            "void png_read(void) { PROBE(__func__);\n    work();\n}\nvoid other(void) {}\n"
+        );
+        // Idempotent
+        assert!(super::probe_edits(&patched, &probe).is_empty());
+    }
+}
@@ -0,0 +1,654 @@
+//! Removal of declarations unreferenced in preprocessed C sources.
+//!
+//! Top-level declarations are split by tokens, and ones whose names are not reachable from
+//! declarations which must be kept (e.g. functions with external linkage) are removed.
+//! Removed lines are followed by a line marker, so that diagnostics keep pointing to the
+//! original locations.
+
+use crate::preprocessor::path_like::PathLike;
+use crate::preprocessor::{ForcedIncludes, Line, Preprocessor};
+use crate::tokenizer::{self, Token, TokenKind};
+use crate::Result;
+
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+
+/// Numbers of declarations removed by `prune()`
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct PruneStats {
+    /// Definitions of static functions
+    pub functions: usize,
+    /// Static variables and `extern` declarations of variables
+    pub variables: usize,
+    /// Function declarations without bodies
+    pub prototypes: usize,
+    pub typedefs: usize,
+    /// Definitions and forward declarations of structs, unions and enums
+    pub records: usize,
+    pub lines: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Function,
+    Variable,
+    Prototype,
+    Typedef,
+    Record,
+}
+
+#[derive(Debug)]
+struct Declaration<'a> {
+    /// Range of significant tokens
+    range: Range<usize>,
+    /// `None` if the declaration is kept regardless of references
+    kind: Option<Kind>,
+    names: Vec<&'a str>,
+    references: HashSet<&'a str>,
+}
+
+/// Keywords followed by parenthesized groups which are not declarators
+const GROUP_KEYWORDS: [&str; 13] = [
+    "__attribute__",
+    "__attribute",
+    "__declspec",
+    "__asm__",
+    "__asm",
+    "asm",
+    "_Alignas",
+    "alignas",
+    "__typeof__",
+    "__typeof",
+    "typeof",
+    "_Static_assert",
+    "static_assert",
+];
+
+/// Keywords which are never names of declarations
+const KEYWORDS: [&str; 38] = [
+    "void",
+    "char",
+    "short",
+    "int",
+    "long",
+    "float",
+    "double",
+    "signed",
+    "unsigned",
+    "_Bool",
+    "bool",
+    "_Complex",
+    "__int128",
+    "__signed__",
+    "const",
+    "__const",
+    "volatile",
+    "__volatile__",
+    "restrict",
+    "__restrict",
+    "__restrict__",
+    "_Atomic",
+    "static",
+    "extern",
+    "inline",
+    "__inline",
+    "__inline__",
+    "register",
+    "auto",
+    "typedef",
+    "struct",
+    "union",
+    "enum",
+    "_Noreturn",
+    "__extension__",
+    "_Thread_local",
+    "__thread",
+    "thread_local",
+];
+
+/// Attributes which keep declarations even if they are not referenced
+const RETAINING_ATTRIBUTES: [&str; 8] = [
+    "used",
+    "__used__",
+    "constructor",
+    "__constructor__",
+    "destructor",
+    "__destructor__",
+    "alias",
+    "__alias__",
+];
+
+fn is_group_keyword(token: &Token) -> bool {
+    GROUP_KEYWORDS.contains(&token.text)
+}
+
+/// Index of the closing bracket if `tokens[open]` opens a bracket
+fn closing(tokens: &[Token], open: usize) -> Option<usize> {
+    if open < tokens.len() {
+        tokenizer::matching_bracket(tokens, open)
+    } else {
+        None
+    }
+}
+
+/// Index of the first `(` at depth 0 opening a declarator or a parameter list, before any
+/// initializer
+fn declarator_paren(tokens: &[Token]) -> Option<usize> {
+    let mut depth: usize = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        match token.text {
+            "(" if depth == 0 && !(i > 0 && is_group_keyword(&tokens[i - 1])) => return Some(i),
+            "=" if depth == 0 => return None,
+            "(" | "[" | "{" => depth += 1,
+            ")" | "]" | "}" => depth = depth.saturating_sub(1),
+            _ => (),
+        }
+    }
+    None
+}
+
+/// Whether `tokens` are the head of a K&R-style function definition before parameter
+/// declarations, e.g. `int f(a) int a`
+fn is_knr_head(tokens: &[Token]) -> bool {
+    declarator_paren(tokens)
+        .and_then(|open| closing(tokens, open))
+        .and_then(|close| tokens.get(close + 1))
+        .is_some_and(|next| next.kind == TokenKind::Identifier && !is_group_keyword(next))
+}
+
+/// Split top-level declarations. Directives between declarations are not included.
+fn split_declarations(tokens: &[Token]) -> Vec<Range<usize>> {
+    let mut result = Vec::new();
+    let mut start: Option<usize> = None;
+    let mut depth: usize = 0;
+    let mut function_body = false;
+    for (i, token) in tokens.iter().enumerate() {
+        let first = match start {
+            Some(first) => first,
+            None if token.kind == TokenKind::Directive || token.is(";") => continue,
+            None => {
+                start = Some(i);
+                i
+            }
+        };
+        match token.text {
+            "(" | "[" => depth += 1,
+            ")" | "]" => depth = depth.saturating_sub(1),
+            "{" => {
+                if depth == 0 && declarator_paren(&tokens[first..i]).is_some() {
+                    function_body = true;
+                }
+                depth += 1;
+            }
+            "}" => {
+                depth = depth.saturating_sub(1);
+                if depth == 0 && function_body {
+                    result.push(first..i + 1);
+                    start = None;
+                    function_body = false;
+                }
+            }
+            ";" if depth == 0 && !is_knr_head(&tokens[first..i]) => {
+                result.push(first..i + 1);
+                start = None;
+            }
+            _ => (),
+        }
+    }
+    if let Some(first) = start {
+        result.push(first..tokens.len());
+    }
+    result
+}
+
+/// Name of a declarator and whether it declares a function
+fn declarator<'a>(tokens: &[Token<'a>]) -> (Option<&'a str>, bool) {
+    let mut name = None;
+    let mut i = 0;
+    while i < tokens.len() {
+        let token = &tokens[i];
+        if token.is("=") || token.is("[") || token.is(":") {
+            break;
+        }
+        if is_group_keyword(token) {
+            i = match tokens.get(i + 1) {
+                Some(next) if next.is("(") => {
+                    closing(tokens, i + 1).map_or(tokens.len(), |v| v + 1)
+                }
+                _ => i + 1,
+            };
+            continue;
+        }
+        if token.is("(") {
+            let close = closing(tokens, i).unwrap_or(tokens.len());
+            // Parenthesized declarator such as `(*handler)(int)`
+            if tokens
+                .get(i + 1)
+                .is_some_and(|v| v.is("*") || v.is("^") || v.is("("))
+            {
+                return declarator(&tokens[i + 1..close]);
+            }
+            return (name, name.is_some());
+        }
+        if token.kind == TokenKind::Identifier && !KEYWORDS.contains(&token.text) {
+            name = Some(token.text);
+        }
+        i += 1;
+    }
+    (name, false)
+}
+
+/// Tag, enumerators and the closing brace of the struct, union or enum defined in `tokens`
+fn record<'a>(tokens: &[Token<'a>]) -> Option<(Option<&'a str>, Vec<&'a str>, Option<usize>)> {
+    let keyword = tokens
+        .iter()
+        .position(|v| v.is("struct") || v.is("union") || v.is("enum"))?;
+    let mut i = keyword + 1;
+    let mut tag = None;
+    while let Some(token) = tokens.get(i) {
+        if is_group_keyword(token) {
+            i = match tokens.get(i + 1) {
+                Some(next) if next.is("(") => closing(tokens, i + 1)? + 1,
+                _ => i + 1,
+            };
+        } else if token.kind == TokenKind::Identifier && tag.is_none() {
+            tag = Some(token.text);
+            i += 1;
+        } else {
+            break;
+        }
+    }
+    if !tokens.get(i).is_some_and(|v| v.is("{")) {
+        return Some((tag, vec![], None));
+    }
+    let close = closing(tokens, i)?;
+    let mut enumerators = Vec::new();
+    if tokens[keyword].is("enum") {
+        let mut depth: usize = 0;
+        for (j, token) in tokens[..close].iter().enumerate().skip(i) {
+            match token.text {
+                "(" | "[" | "{" => depth += 1,
+                ")" | "]" | "}" => depth = depth.saturating_sub(1),
+                _ if depth == 1
+                    && token.kind == TokenKind::Identifier
+                    && (tokens[j - 1].is("{") || tokens[j - 1].is(",")) =>
+                {
+                    enumerators.push(token.text)
+                }
+                _ => (),
+            }
+        }
+    }
+    Some((tag, enumerators, Some(close)))
+}
+
+/// Split declarators at commas at depth 0
+pub(crate) fn split_declarators<'a, 'b>(tokens: &'b [Token<'a>]) -> Vec<&'b [Token<'a>]> {
+    let mut result = Vec::new();
+    let mut depth: usize = 0;
+    let mut start = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        match token.text {
+            "(" | "[" | "{" => depth += 1,
+            ")" | "]" | "}" => depth = depth.saturating_sub(1),
+            "," | ";" if depth == 0 => {
+                result.push(&tokens[start..i]);
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    if start < tokens.len() {
+        result.push(&tokens[start..]);
+    }
+    result.retain(|v| !v.is_empty());
+    result
+}
+
+/// Identifiers in directives, except line markers and `#include`
+fn directive_identifiers<'a>(token: &Token<'a>) -> Vec<&'a str> {
+    let body = token.text[1..].trim_start();
+    if body.starts_with(|c: char| c.is_ascii_digit())
+        || body.starts_with("line")
+        || body.starts_with("include")
+    {
+        return vec![];
+    }
+    tokenizer::tokenize(body)
+        .into_iter()
+        .filter(|v| v.kind == TokenKind::Identifier)
+        .map(|v| v.text)
+        .collect()
+}
+
+fn analyze<'a>(tokens: &[Token<'a>], range: Range<usize>) -> Declaration<'a> {
+    let d = &tokens[range.clone()];
+    let mut top_level = Vec::new();
+    let mut depth: usize = 0;
+    for token in d.iter() {
+        if depth == 0 {
+            top_level.push(token.text);
+        }
+        match token.text {
+            "(" | "[" | "{" => depth += 1,
+            ")" | "]" | "}" => depth = depth.saturating_sub(1),
+            _ => (),
+        }
+    }
+    let has = |text: &str| top_level.contains(&text);
+    let retained = d.iter().any(|v| RETAINING_ATTRIBUTES.contains(&v.text));
+
+    let mut names = Vec::new();
+    let kind = if d.last().is_some_and(|v| v.is("}")) {
+        // Function definition
+        let body = d.iter().position(|v| v.is("{")).unwrap_or(d.len());
+        let (name, _) = declarator(
+            &d[..declarator_paren(d)
+                .map_or(body, |v| closing(d, v).map_or(body, |close| close + 1))],
+        );
+        names.extend(name);
+        has("static").then_some(Kind::Function)
+    } else {
+        let record = record(d);
+        let declarators_start = match record {
+            Some((_, _, Some(close))) => close + 1,
+            _ => 0,
+        };
+        let declarators: Vec<(Option<&str>, bool, bool)> =
+            split_declarators(&d[declarators_start..])
+                .into_iter()
+                .map(|v| {
+                    let (name, function) = declarator(v);
+                    (name, function, v.iter().any(|v| v.is("=")))
+                })
+                .collect();
+        let forward = match record {
+            Some((Some(tag), _, None)) => {
+                declarators.len() == 1 && declarators[0].0 == Some(tag) && !declarators[0].1
+            }
+            _ => false,
+        };
+        if let Some((tag, ref enumerators, _)) = record {
+            names.extend(tag);
+            names.extend(enumerators.iter().copied());
+        }
+        if forward {
+            Some(Kind::Record)
+        } else {
+            names.extend(declarators.iter().filter_map(|v| v.0));
+            if has("typedef") {
+                Some(Kind::Typedef)
+            } else if declarators.is_empty() {
+                record
+                    .is_some_and(|(_, _, close)| close.is_some())
+                    .then_some(Kind::Record)
+            } else if declarators.iter().any(|v| v.0.is_none()) {
+                None
+            } else if declarators.iter().all(|v| v.1) {
+                Some(Kind::Prototype)
+            } else if declarators
+                .iter()
+                .all(|v| v.1 || has("static") || (has("extern") && !v.2))
+            {
+                Some(Kind::Variable)
+            } else {
+                None
+            }
+        }
+    };
+    let kind = kind.filter(|_| !retained && !names.is_empty());
+
+    let mut references: HashSet<&str> = HashSet::new();
+    for token in d.iter() {
+        match token.kind {
+            TokenKind::Identifier => {
+                references.insert(token.text);
+            }
+            TokenKind::Directive => references.extend(directive_identifiers(token)),
+            // Targets of `alias("name")`
+            TokenKind::String if retained => {
+                references.insert(token.text.trim_matches('"'));
+            }
+            _ => (),
+        }
+    }
+    for name in names.iter() {
+        references.remove(name);
+    }
+    Declaration {
+        range,
+        kind,
+        names,
+        references,
+    }
+}
+
+/// Remove unreferenced static functions, static variables, `extern` declarations, prototypes,
+/// typedefs and struct, union and enum definitions from preprocessed C source.
+/// Declarations sharing lines with other code are kept.
+pub fn prune(source: &str) -> Result<(String, PruneStats)> {
+    let tokens = tokenizer::significant_tokens(source);
+    let declarations: Vec<Declaration> = split_declarations(&tokens)
+        .into_iter()
+        .map(|range| analyze(&tokens, range))
+        .collect();
+
+    let line_starts: Vec<usize> = std::iter::once(0)
+        .chain(source.match_indices('\n').map(|(i, _)| i + 1))
+        .collect();
+    let line_of = |offset: usize| line_starts.partition_point(|v| *v <= offset) - 1;
+
+    // Declaration owning all significant tokens in each line. Lines with directives, tokens
+    // outside declarations or tokens of multiple declarations are `Mixed`.
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    enum Owner {
+        Empty,
+        Sole(usize),
+        Mixed,
+    }
+    let mut owners = vec![Owner::Empty; line_starts.len()];
+    let mut owner_of_token: Vec<Option<usize>> = vec![None; tokens.len()];
+    for (index, declaration) in declarations.iter().enumerate() {
+        for v in owner_of_token[declaration.range.clone()].iter_mut() {
+            *v = Some(index);
+        }
+    }
+    for (token, owner) in tokens.iter().zip(owner_of_token.iter()) {
+        let owner = match owner {
+            Some(v) if token.kind != TokenKind::Directive => Owner::Sole(*v),
+            _ => Owner::Mixed,
+        };
+        let lines = line_of(token.start)..=line_of(token.end().saturating_sub(1));
+        for v in owners[lines].iter_mut() {
+            *v = match *v {
+                Owner::Empty => owner,
+                v if v == owner => v,
+                _ => Owner::Mixed,
+            };
+        }
+    }
+    let lines_of = |declaration: &Declaration| {
+        let first = &tokens[declaration.range.start];
+        let last = &tokens[declaration.range.end - 1];
+        line_of(first.start)..line_of(last.end().saturating_sub(1)) + 1
+    };
+    let removable: Vec<bool> = declarations
+        .iter()
+        .enumerate()
+        .map(|(index, declaration)| {
+            declaration.kind.is_some()
+                && lines_of(declaration).all(|line| {
+                    matches!(owners[line], Owner::Sole(v) if v == index)
+                        || owners[line] == Owner::Empty
+                })
+        })
+        .collect();
+
+    // Mark declarations reachable from ones which are kept anyway
+    let mut declarations_of: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (index, declaration) in declarations.iter().enumerate() {
+        for name in declaration.names.iter() {
+            declarations_of.entry(name).or_default().push(index);
+        }
+    }
+    let mut live: Vec<bool> = removable.iter().map(|v| !v).collect();
+    let mut pending: Vec<&str> = tokens
+        .iter()
+        .zip(owner_of_token.iter())
+        .filter(|(token, owner)| owner.is_none() && token.kind == TokenKind::Directive)
+        .flat_map(|(token, _)| directive_identifiers(token))
+        .collect();
+    for (index, declaration) in declarations.iter().enumerate() {
+        if live[index] {
+            pending.extend(declaration.references.iter().copied());
+        }
+    }
+    let mut referenced: HashSet<&str> = HashSet::new();
+    while let Some(name) = pending.pop() {
+        if !referenced.insert(name) {
+            continue;
+        }
+        for index in declarations_of.get(name).into_iter().flatten() {
+            if !live[*index] {
+                live[*index] = true;
+                pending.extend(declarations[*index].references.iter().copied());
+            }
+        }
+    }
+
+    let mut stats = PruneStats::default();
+    let mut dropped = vec![false; line_starts.len()];
+    for (index, declaration) in declarations.iter().enumerate() {
+        if live[index] {
+            continue;
+        }
+        match declaration.kind {
+            Some(Kind::Function) => stats.functions += 1,
+            Some(Kind::Variable) => stats.variables += 1,
+            Some(Kind::Prototype) => stats.prototypes += 1,
+            Some(Kind::Typedef) => stats.typedefs += 1,
+            Some(Kind::Record) => stats.records += 1,
+            None => continue,
+        }
+        for line in lines_of(declaration) {
+            dropped[line] = true;
+        }
+    }
+
+    // Resynchronize line numbers after removed lines with line markers
+    let mut result = String::new();
+    let mut resync = false;
+    for (index, line) in Preprocessor::annotate(source, &ForcedIncludes::default())?
+        .into_iter()
+        .enumerate()
+    {
+        if dropped[index] {
+            stats.lines += 1;
+            resync = true;
+            continue;
+        }
+        if resync {
+            if let (Line::Other(_), Some(context), Some(source_line)) =
+                (&line.line, &line.context, line.source_line)
+            {
+                result.push_str(&format!(
+                    "# {} \"{}\"\n",
+                    source_line,
+                    context.path().display()
+                ));
+            }
+            resync = false;
+        }
+        result.push_str(&line.text);
+        result.push('\n');
+    }
+    Ok((result, stats))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prune() {
+        let source = r#"# 1 "main.c"
+# 1 "calc.h" 1
+typedef int unused_t;
+typedef int used_t;
+struct unused_s { int a; };
+struct used_s { used_t a; };
+enum { UNUSED_A, UNUSED_B };
+static inline int unused_f(void) { return 0; }
+static inline int helper(void) { return 1; }
+static inline int chain(void)
+{
+    return helper();
+}
+int prototype(void);
+extern int unused_var;
+static int counter; static int unused_counter;
+static void (*handler)(int);
+__attribute__((constructor)) static void init(void) {}
+# 3 "main.c" 2
+int main(void) {
+    struct used_s s = {0};
+    return chain() + s.a + counter;
+}
+"#;
+        let (pruned, stats) = super::prune(source).unwrap();
+        assert_eq!(
+            pruned,
+            r#"# 1 "main.c"
+# 1 "calc.h" 1
+# 2 "calc.h"
+typedef int used_t;
+# 4 "calc.h"
+struct used_s { used_t a; };
+# 7 "calc.h"
+static inline int helper(void) { return 1; }
+static inline int chain(void)
+{
+    return helper();
+}
+# 14 "calc.h"
+static int counter; static int unused_counter;
+# 16 "calc.h"
+__attribute__((constructor)) static void init(void) {}
+# 3 "main.c" 2
+int main(void) {
+    struct used_s s = {0};
+    return chain() + s.a + counter;
+}
+"#
+        );
+        assert_eq!(
+            stats,
+            PruneStats {
+                functions: 1,
+                variables: 2,
+                prototypes: 1,
+                typedefs: 1,
+                records: 2,
+                lines: 7,
+            }
+        );
+    }
+
+    #[test]
+    fn split_declarations() {
+        let source = "int f(a, b) int a; char b; { return a + b; }\nstruct s { int x; } v, *p;\nint g(void) __attribute__((noreturn));";
+        let tokens = tokenizer::significant_tokens(source);
+        let declarations = super::split_declarations(&tokens);
+        assert_eq!(declarations.len(), 3);
+        let knr = analyze(&tokens, declarations[0].clone());
+        assert_eq!(knr.names, vec!["f"]);
+        assert_eq!(knr.kind, None);
+        let record = analyze(&tokens, declarations[1].clone());
+        assert_eq!(record.names, vec!["s", "v", "p"]);
+        assert_eq!(record.kind, None);
+        let prototype = analyze(&tokens, declarations[2].clone());
+        assert_eq!(prototype.names, vec!["g"]);
+        assert_eq!(prototype.kind, Some(Kind::Prototype));
+    }
+}
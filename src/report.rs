@@ -0,0 +1,148 @@
+use crate::edit::Edit;
+use crate::preprocessor::PreprocessStats;
+use crate::prune::PruneStats;
+use crate::rule::Rule;
+
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// 1-based line and column. Columns are counted in bytes.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Position {
+    /// Position of byte `offset` in `source`
+    pub fn new(source: &str, offset: usize) -> Self {
+        let line_start = source[..offset].rfind('\n').map_or(0, |v| v + 1);
+        Position {
+            line: source[..offset].matches('\n').count() + 1,
+            column: offset - line_start + 1,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: Position,
+    pub end: Position,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct RuleReport {
+    /// Number of edits
+    pub count: usize,
+    /// Ranges replaced by the rule, in the content before the rule is applied
+    pub locations: Vec<Span>,
+}
+
+/// Result of `--verify` of a translation unit
+#[derive(Serialize, Debug, Default)]
+pub struct Verification {
+    pub passed: bool,
+    /// Whether the translation unit is restored to the original, with its headers not included
+    /// by translation units which passed
+    pub rolled_back: bool,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub diagnostics: String,
+}
+
+/// Result of `--compare-objects` of a translation unit
+#[derive(Serialize, Debug, Default)]
+pub struct ObjectComparison {
+    /// Whether all `.text` and `.data` sections are identical
+    pub equivalent: bool,
+    pub different_sections: Vec<String>,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct FileReport {
+    pub rules: BTreeMap<String, RuleReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preprocessing: Option<PreprocessStats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pruning: Option<PruneStats>,
+    /// Number of snippets inserted by `--canary-spec`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub canaries: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verification: Option<Verification>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub object_comparison: Option<ObjectComparison>,
+}
+
+impl FileReport {
+    /// Record `edits` which `rule` made on `source`
+    pub fn record(&mut self, rule: Rule, source: &str, edits: &[Edit]) {
+        let line_starts: Vec<usize> = std::iter::once(0)
+            .chain(source.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        let position = |offset: usize| {
+            let line = line_starts.partition_point(|v| *v <= offset);
+            Position {
+                line,
+                column: offset - line_starts[line - 1] + 1,
+            }
+        };
+        let report = self.rules.entry(rule.name().to_string()).or_default();
+        report.count += edits.len();
+        report.locations.extend(edits.iter().map(|edit| Span {
+            start: position(edit.range.start),
+            end: position(edit.range.end),
+        }));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+            && self.preprocessing.is_none()
+            && self.pruning.is_none()
+            && self.canaries.is_none()
+            && self.verification.is_none()
+            && self.object_comparison.is_none()
+    }
+}
+
+/// Changes made in files, keyed on canonical file paths.
+#[derive(Serialize, Debug, Default)]
+pub struct Report {
+    pub files: BTreeMap<PathBuf, FileReport>,
+    /// Files which failed to be preprocessed, patched or verified
+    #[serde(skip_serializing_if = "BTreeSet::is_empty")]
+    pub failed_files: BTreeSet<PathBuf>,
+}
+
+impl Report {
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record() {
+        let source = "int *p = NULL;\nf(NULL);\n";
+        let edits = vec![Edit::new(9..13, "(NULL)"), Edit::new(17..21, "(NULL)")];
+        let mut report = FileReport::default();
+        report.record(Rule::Null, source, &edits);
+        report.record(Rule::Null, source, &edits[..1]);
+        let rule = &report.rules["null"];
+        assert_eq!(rule.count, 3);
+        assert_eq!(
+            rule.locations[1],
+            Span {
+                start: Position { line: 2, column: 3 },
+                end: Position { line: 2, column: 7 },
+            }
+        );
+        assert!(!report.is_empty());
+        assert_eq!(Position::new(source, 17), rule.locations[1].start);
+    }
+}
@@ -0,0 +1,68 @@
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+/// Built-in rewrite rules recorded in the marker comment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Rule {
+    Preprocessor,
+    Null,
+    Constexpr,
+    SingleQuote,
+    Probe,
+}
+
+impl Rule {
+    /// Rules rewriting text, in the order they are applied
+    pub const TEXT_RULES: [Rule; 4] = [Rule::Null, Rule::Constexpr, Rule::SingleQuote, Rule::Probe];
+
+    /// Rules applied unless `--rules` is given. `Rule::Probe` is enabled by `--probe`.
+    pub const DEFAULT_RULES: [Rule; 3] = [Rule::Null, Rule::Constexpr, Rule::SingleQuote];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Rule::Preprocessor => "preprocessor",
+            Rule::Null => "null",
+            Rule::Constexpr => "constexpr",
+            Rule::SingleQuote => "single-quote",
+            Rule::Probe => "probe",
+        }
+    }
+
+    /// Parse a rule rewriting text (i.e. other than `preprocessor`)
+    pub fn parse_text_rule(s: &str) -> Result<Self, String> {
+        match s.parse()? {
+            Rule::Preprocessor => Err(String::from(
+                "preprocessor is not a text rule. Use `preprocess` subcommand",
+            )),
+            rule => Ok(rule),
+        }
+    }
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "preprocessor" => Ok(Rule::Preprocessor),
+            "null" => Ok(Rule::Null),
+            "constexpr" => Ok(Rule::Constexpr),
+            "single-quote" => Ok(Rule::SingleQuote),
+            "probe" => Ok(Rule::Probe),
+            _ => Err(format!("Unknown rule: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl Serialize for Rule {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
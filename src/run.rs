@@ -0,0 +1,950 @@
+use crate::amalgamation::{self, Amalgamation, Unit};
+use crate::backup::Backup;
+use crate::bisect::{self, BisectOutcome};
+use crate::cache::{self, Cache, CacheEntry};
+use crate::canary::CanarySpec;
+use crate::compile_command::CompileCommand;
+use crate::filter::Filter;
+use crate::injection::{self, DefinesStyle, TemplateVariables};
+use crate::language::{FileType, LanguageMap};
+use crate::marker;
+use crate::objects;
+use crate::options::{Options, RuleOptions, Selection};
+use crate::patch::{self, RuleEdit};
+use crate::preprocessor::include_graph::IncludeGraph;
+use crate::preprocessor::{ForcedIncludeMode, PreprocessStats, Preprocessor};
+use crate::prune::{self, PruneStats};
+use crate::report::{FileReport, ObjectComparison, Report, Verification};
+use crate::rule::Rule;
+use crate::{Error, Result};
+
+#[allow(unused_imports)]
+use log::{error, info, trace, warn};
+#[allow(unused_imports)]
+use rayon::prelude::*;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// File to be rewritten
+#[derive(Debug)]
+pub struct TargetFile {
+    pub path: PathBuf,
+    pub file_type: Option<FileType>,
+    /// Base of relative paths: `directory` of the compile command, or current directory
+    pub root: PathBuf,
+}
+
+/// Project headers of translation units and their languages
+type Headers = Vec<(PathBuf, FileType)>;
+
+fn filter(selection: &Selection) -> Result<Filter> {
+    Filter::new(
+        selection.only.clone(),
+        selection.only_regex.clone(),
+        selection.only_from.clone(),
+        selection.ignore.clone(),
+        selection.ignore_regex.clone(),
+        selection.ignore_from.clone(),
+    )
+}
+
+fn language_map(selection: &Selection) -> Result<LanguageMap> {
+    LanguageMap::new(&selection.lang_map).map_err(Error::OptionError)
+}
+
+/// Entries of `selection.compile_commands` selected by `filter`, one per file
+fn compile_commands(selection: &Selection, filter: &Filter) -> Result<Vec<CompileCommand>> {
+    let compile_commands = CompileCommand::load(&selection.compile_commands)?;
+    if compile_commands.is_empty() {
+        return Err(Error::OptionError(format!(
+            "No entries in compile commands: {:?}",
+            selection.compile_commands
+        )));
+    }
+
+    // Filter out commands for same file
+    let mut unduplicated_compile_commands = Vec::new();
+    let mut done_list = HashSet::new();
+    for command in compile_commands.into_iter() {
+        if done_list.contains(&command.file) {
+            trace!(
+                "Another command for same file. Skip: file={:?}, arguments={:?}, command={:?}",
+                command.file,
+                command.arguments,
+                command.command
+            );
+            continue;
+        }
+        if !filter.is_selected(&command.file, &command.directory) {
+            trace!("Ignore command: file={:?}", command.file);
+            continue;
+        }
+        done_list.insert(command.file.clone());
+        unduplicated_compile_commands.push(command);
+    }
+    info!("Total #commands: {}", unduplicated_compile_commands.len());
+    Ok(unduplicated_compile_commands)
+}
+
+fn canonicalize(path: &Path) -> Result<PathBuf> {
+    path.canonicalize()
+        .map_err(|_| Error::OptionError(format!("Failed to open file: {:?}", path)))
+}
+
+/// `selection.files` selected by `filter`
+fn files_from_args(
+    selection: &Selection,
+    filter: &Filter,
+    current_dir: &Path,
+) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for file in selection.files.iter() {
+        let file = canonicalize(file)?;
+        if !filter.is_selected(&file, current_dir) {
+            trace!("Ignore file: file={:?}", file);
+            continue;
+        }
+        files.push(file);
+    }
+    Ok(files)
+}
+
+fn classify_headers(
+    headers: Vec<PathBuf>,
+    file_type: FileType,
+    language_map: &LanguageMap,
+) -> Headers {
+    headers
+        .into_iter()
+        .map(|v| {
+            let header_type = language_map.classify_header(&v, file_type);
+            (v, header_type)
+        })
+        .collect()
+}
+
+/// Project headers included by `command` and selected by `filter`
+fn project_headers(command: &CompileCommand, filter: &Filter) -> Result<Vec<PathBuf>> {
+    Ok(command
+        .included_headers()?
+        .into_iter()
+        .filter(|v| filter.is_selected(v, &command.directory))
+        .collect())
+}
+
+/// Files to be rewritten, sorted by path. Files given by arguments are included even if their
+/// language is unknown.
+fn target_files(
+    compile_commands: &[CompileCommand],
+    files_from_args: &[PathBuf],
+    headers_of_compile_commands: &HashMap<PathBuf, Headers>,
+    language_map: &LanguageMap,
+    current_dir: &Path,
+) -> Vec<TargetFile> {
+    let mut target_files: Vec<TargetFile> = Vec::new();
+    let mut seen: HashSet<PathBuf> = HashSet::new();
+    let mut push = |path: &Path, file_type: Option<FileType>, root: &Path| {
+        // Keep the first one found, e.g. a header also given by arguments
+        if seen.insert(path.to_path_buf()) {
+            target_files.push(TargetFile {
+                path: path.to_path_buf(),
+                file_type,
+                root: root.to_path_buf(),
+            });
+        }
+    };
+    for command in compile_commands.iter() {
+        if let Some(file_type) = command.file_type(language_map) {
+            push(&command.file, Some(file_type), &command.directory);
+        }
+    }
+    for file in files_from_args.iter() {
+        push(file, language_map.classify(file, None), current_dir);
+    }
+    for command in compile_commands.iter() {
+        for (header, file_type) in headers_of_compile_commands
+            .get(&command.file)
+            .into_iter()
+            .flatten()
+        {
+            push(header, Some(*file_type), &command.directory);
+        }
+    }
+    target_files.sort_by(|a, b| a.path.cmp(&b.path));
+    target_files
+}
+
+/// Files restored by `--rollback-on-failure`: translation units failing verification, and
+/// headers included only by them. Headers also included by other translation units are kept so
+/// that those are still built against the verified headers.
+fn rollback_files(
+    headers_of_compile_commands: &HashMap<PathBuf, Headers>,
+    failed: &HashSet<&PathBuf>,
+) -> BTreeSet<PathBuf> {
+    let mut includers: HashMap<&PathBuf, Vec<&PathBuf>> = HashMap::new();
+    for (file, headers) in headers_of_compile_commands.iter() {
+        for (header, _) in headers.iter() {
+            includers.entry(header).or_default().push(file);
+        }
+    }
+    let mut files: BTreeSet<PathBuf> = failed.iter().map(|v| (*v).clone()).collect();
+    for (header, includers) in includers.into_iter() {
+        if includers.iter().all(|v| failed.contains(v)) {
+            files.insert(header.clone());
+        }
+    }
+    files
+}
+
+/// Files which `run()` would rewrite with `selection`
+pub fn list_files(selection: &Selection) -> Result<Vec<TargetFile>> {
+    let filter = filter(selection)?;
+    let language_map = language_map(selection)?;
+    let compile_commands = compile_commands(selection, &filter)?;
+    let current_dir = env::current_dir()?;
+    let files_from_args = files_from_args(selection, &filter, &current_dir)?;
+
+    let mut headers_of_compile_commands: HashMap<PathBuf, Headers> = HashMap::new();
+    if selection.headers {
+        let result: Vec<_> = compile_commands
+            .par_iter()
+            .filter_map(|command| {
+                let file_type = command.file_type(&language_map).filter(|v| v.is_source())?;
+                Some((command, file_type, project_headers(command, &filter)))
+            })
+            .collect();
+        for (command, file_type, v) in result.into_iter() {
+            match v {
+                Ok(headers) => {
+                    headers_of_compile_commands.insert(
+                        command.file.clone(),
+                        classify_headers(headers, file_type, &language_map),
+                    );
+                }
+                Err(err) => error!(
+                    "Failed to discover headers: file={:?}, err={:?}",
+                    command.file, err
+                ),
+            }
+        }
+    }
+
+    Ok(target_files(
+        &compile_commands,
+        &files_from_args,
+        &headers_of_compile_commands,
+        &language_map,
+        &current_dir,
+    ))
+}
+
+/// Patch files in `options.selection`.
+/// Files which fail to be patched are recorded in `Report::failed_files`.
+pub fn run(options: &Options) -> Result<Report> {
+    let selection = &options.selection;
+    // Definitions of protected macros are expanded away with inlined forced headers
+    if options.forced_includes == ForcedIncludeMode::Inline && !options.protect_macro.is_empty() {
+        return Err(Error::OptionError(String::from(
+            "--protect-macro cannot be used with --forced-includes inline",
+        )));
+    }
+    let checks = if options.dry_run {
+        if options.checks.verify || options.checks.compare_objects {
+            warn!("Checks of translation units are skipped in dry run");
+        }
+        Default::default()
+    } else {
+        options.checks.clone()
+    };
+    let backup = if options.backup.no_backup || options.dry_run {
+        None
+    } else {
+        Some(Backup::new(&options.backup.backup_dir))
+    };
+    let write_file = |path: &Path, content: &str| -> Result<()> {
+        if let Some(ref backup) = backup {
+            backup.save(path, content)?;
+        }
+        fs::write(path, content)?;
+        Ok(())
+    };
+
+    let fingerprint = options.fingerprint();
+    let cache = if options.incremental && !options.dry_run {
+        Some(Cache::load(&options.cache))
+    } else {
+        None
+    };
+    let is_fresh = |file: &Path, arguments: Option<&Vec<String>>| -> Option<&CacheEntry> {
+        cache
+            .as_ref()
+            .and_then(|v| v.fresh_entry(file, arguments, &fingerprint))
+    };
+
+    let filter = filter(selection)?;
+    let language_map = language_map(selection)?;
+    let compile_commands = compile_commands(selection, &filter)?;
+
+    // Keep original contents to roll back files failing verification
+    let mut originals: HashMap<PathBuf, String> = HashMap::new();
+    if checks.rollback_on_failure {
+        for command in compile_commands.iter() {
+            originals.insert(command.file.clone(), fs::read_to_string(&command.file)?);
+        }
+    }
+
+    // Compile original translation units to compare with patched ones
+    let objects_dir = env::temp_dir().join(format!("special-patch-{}", std::process::id()));
+    let mut original_objects: HashMap<PathBuf, PathBuf> = HashMap::new();
+    if checks.compare_objects {
+        fs::create_dir_all(&objects_dir)?;
+        let result: Vec<_> = compile_commands
+            .par_iter()
+            .enumerate()
+            .filter(|(_, v)| v.file_type(&language_map).is_some_and(|v| v.is_source()))
+            .map(|(index, command)| {
+                let output = objects_dir.join(format!("{}.original.o", index));
+                (command, command.compile_object(&output).map(|_| output))
+            })
+            .collect();
+        for (command, v) in result.into_iter() {
+            match v {
+                Ok(output) => {
+                    original_objects.insert(command.file.clone(), output);
+                }
+                Err(err) => warn!(
+                    "Failed to compile original file. Skip comparison: file={:?}, err={:?}",
+                    command.file, err
+                ),
+            }
+        }
+    }
+
+    // Insert canaries before preprocessor expands them
+    let current_dir = env::current_dir()?;
+    let files_from_args = files_from_args(selection, &filter, &current_dir)?;
+    // Preprocessed sources not written in dry run
+    let mut pending_sources: HashMap<PathBuf, String> = HashMap::new();
+    let mut canaries: HashMap<PathBuf, usize> = HashMap::new();
+    if let Some(ref path) = options.canary_spec {
+        let spec = CanarySpec::load(path)?;
+        let unselected: Vec<&Path> = spec
+            .files()
+            .into_iter()
+            .filter(|file| {
+                !(compile_commands.iter().any(|v| v.file == *file)
+                    || files_from_args.iter().any(|v| v == file)
+                    || selection.headers && filter.is_selected(file, &current_dir))
+            })
+            .collect();
+        if !unselected.is_empty() {
+            return Err(Error::CanarySpecError(format!(
+                "Files are not selected: {:?}",
+                unselected
+            )));
+        }
+        if options.dry_run && options.preprocessor {
+            warn!("Canaries are not preprocessed in dry run");
+        }
+        for (file, canary_file) in spec.apply(options.include_style, options.include_placement)? {
+            if canary_file.inserted == 0 {
+                continue;
+            }
+            if checks.rollback_on_failure && !originals.contains_key(&file) {
+                originals.insert(file.clone(), fs::read_to_string(&file)?);
+            }
+            if options.dry_run {
+                pending_sources.insert(file.clone(), canary_file.text);
+            } else {
+                write_file(&file, &canary_file.text)?;
+            }
+            canaries.insert(file, canary_file.inserted);
+        }
+    }
+
+    // Execute compile_commands.json-depend process
+    type Discovered = (
+        Headers,
+        Option<(String, PreprocessStats, Option<PruneStats>)>,
+    );
+    let mut failed_files = BTreeSet::new();
+    let mut preprocessed_files = HashSet::new();
+    let mut preprocess_stats: HashMap<PathBuf, PreprocessStats> = HashMap::new();
+    let mut prune_stats: HashMap<PathBuf, PruneStats> = HashMap::new();
+    let mut headers_of_compile_commands: HashMap<PathBuf, Headers> = HashMap::new();
+    {
+        let result: Vec<_> = compile_commands
+            .par_iter()
+            .map(|command| -> (&PathBuf, Result<Option<Discovered>>) {
+                trace!("command.file={:?}", command.file);
+                // Only source files are translation units
+                let file_type = match command.file_type(&language_map) {
+                    Some(file_type) if file_type.is_source() => file_type,
+                    _ => return (&command.file, Ok(None)),
+                };
+                let classify_headers =
+                    |headers: Vec<PathBuf>| classify_headers(headers, file_type, &language_map);
+
+                if let Some(entry) = is_fresh(&command.file, command.arguments().ok().as_ref()) {
+                    trace!("Unchanged since last run. Skip: file={:?}", command.file);
+                    return (
+                        &command.file,
+                        Ok(Some((classify_headers(entry.headers.clone()), None))),
+                    );
+                }
+
+                let result = (|| {
+                    // Discover headers before preprocessor inlines them
+                    let headers = if selection.headers {
+                        project_headers(command, &filter)?
+                    } else {
+                        vec![]
+                    };
+
+                    // Apply preprocessor
+                    let mut stats = None;
+                    if options.preprocessor
+                        && !marker::applied_rules(&fs::read_to_string(&command.file)?)
+                            .contains(&Rule::Preprocessor)
+                    {
+                        let (mut preprocessed, v) = command
+                            .preprocessed(&options.protect_macro, options.forced_includes)?;
+                        let mut pruning = None;
+                        if options.prune {
+                            if file_type.is_cxx() {
+                                warn!("Pruning supports only C. Skip: file={:?}", command.file);
+                            } else {
+                                let (pruned, v) = prune::prune(&preprocessed)?;
+                                preprocessed = pruned;
+                                pruning = Some(v);
+                            }
+                        }
+                        if let Some(style) = options.emit_defines {
+                            let defines = command.defines()?;
+                            let referenced = match style {
+                                DefinesStyle::Define => injection::referenced_macros(
+                                    &defines,
+                                    &Preprocessor::system_headers(&preprocessed)?,
+                                ),
+                                DefinesStyle::Comment => HashSet::new(),
+                            };
+                            if let Some(block) =
+                                injection::defines_block(&defines, style, &referenced)
+                            {
+                                preprocessed = block + &preprocessed;
+                            }
+                        }
+                        if !options.dry_run {
+                            write_file(&command.file, &preprocessed)?;
+                        }
+                        stats = Some((preprocessed, v, pruning));
+                    }
+
+                    Ok(Some((classify_headers(headers), stats)))
+                })();
+                (&command.file, result)
+            })
+            .collect();
+        for (file, v) in result.into_iter() {
+            match v {
+                Ok(Some((headers, stats))) => {
+                    if options.preprocessor {
+                        preprocessed_files.insert(file.clone());
+                    }
+                    if let Some((preprocessed, stats, pruning)) = stats {
+                        preprocess_stats.insert(file.clone(), stats);
+                        if let Some(pruning) = pruning {
+                            prune_stats.insert(file.clone(), pruning);
+                        }
+                        if options.dry_run {
+                            pending_sources.insert(file.clone(), preprocessed);
+                        }
+                    }
+                    headers_of_compile_commands.insert(file.clone(), headers);
+                }
+                Ok(None) => (),
+                Err(err) => {
+                    error!(
+                        "Failed to preprocess files in compile_commands.json: file={:?}, err={:?}",
+                        file, err
+                    );
+                    failed_files.insert(file.clone());
+                }
+            }
+        }
+    }
+
+    // Remove forced includes inlined into preprocessed files from compile commands
+    if let Some(ref path) = options.rewritten_compile_commands {
+        if options.forced_includes != ForcedIncludeMode::Inline {
+            warn!("Compile commands are rewritten only with --forced-includes inline");
+        } else if !options.dry_run {
+            let rewritten = CompileCommand::load(&selection.compile_commands)?
+                .into_iter()
+                .map(|command| {
+                    if preprocessed_files.contains(&command.file) {
+                        command.without_forced_includes()
+                    } else {
+                        Ok(command)
+                    }
+                })
+                .collect::<Result<Vec<_>>>()?;
+            fs::write(path, serde_json::to_string_pretty(&rewritten)?)?;
+        }
+    }
+
+    let arguments_of_compile_commands: HashMap<&PathBuf, Vec<String>> = compile_commands
+        .iter()
+        .filter_map(|v| v.arguments().ok().map(|arguments| (&v.file, arguments)))
+        .collect();
+
+    let target_files = target_files(
+        &compile_commands,
+        &files_from_args,
+        &headers_of_compile_commands,
+        &language_map,
+        &current_dir,
+    );
+    if checks.rollback_on_failure {
+        for target in target_files.iter() {
+            if !originals.contains_key(&target.path) {
+                originals.insert(target.path.clone(), fs::read_to_string(&target.path)?);
+            }
+        }
+    }
+
+    let prelude = options
+        .prelude
+        .as_ref()
+        .map(fs::read_to_string)
+        .transpose()?;
+    let epilogue = options
+        .epilogue
+        .as_ref()
+        .map(fs::read_to_string)
+        .transpose()?;
+    let rule_config = options.rule_config()?;
+    let result: Vec<_> = target_files
+        .par_iter()
+        .enumerate()
+        .map(|(index, target)| -> Result<FileReport> {
+            let file_path = &target.path;
+            let file_type = target.file_type;
+            trace!("file_path={:?}, file_type={:?}", file_path, file_type);
+            let preprocessing = preprocess_stats.get(file_path).cloned();
+            let pruning = prune_stats.get(file_path).cloned();
+            let canaries = canaries.get(file_path).copied();
+            if is_fresh(file_path, arguments_of_compile_commands.get(file_path)).is_some() {
+                trace!("Unchanged since last run. Skip: file_path={:?}", file_path);
+                return Ok(FileReport {
+                    preprocessing,
+                    pruning,
+                    canaries,
+                    ..Default::default()
+                });
+            }
+            let original = match pending_sources.get(file_path) {
+                Some(preprocessed) => preprocessed.clone(),
+                None => fs::read_to_string(file_path)?,
+            };
+            let mut text = original.clone();
+
+            // Insert code snippets
+            let variables = TemplateVariables {
+                file: file_path,
+                relative_path: file_path.strip_prefix(&target.root).unwrap_or(file_path),
+                index,
+            };
+            if let Some(ref prelude) = prelude {
+                if let Some(patched) = injection::insert_prelude(
+                    &text,
+                    &variables.render(prelude),
+                    options.prelude_placement,
+                ) {
+                    text = patched;
+                }
+            }
+            if let Some(ref epilogue) = epilogue {
+                if let Some(patched) =
+                    injection::insert_epilogue(&text, &variables.render(epilogue))
+                {
+                    text = patched;
+                }
+            }
+
+            // Insert include file
+            if !options.include.is_empty() {
+                if let Some(patched) = injection::insert_includes(
+                    &text,
+                    &options.include,
+                    options.include_style,
+                    options.include_placement,
+                ) {
+                    text = patched;
+                }
+            }
+
+            // Apply rules. Rules already applied in previous runs are skipped
+            let mut rule_config = rule_config.for_file(file_path);
+            if preprocessed_files.contains(file_path) {
+                rule_config.rules.insert(Rule::Preprocessor);
+            }
+            let patched = patch::patch_buffer(&text, file_type, &rule_config);
+            if patched.text != original && !options.dry_run {
+                write_file(file_path, &patched.text)?;
+            }
+
+            Ok(FileReport {
+                preprocessing,
+                pruning,
+                canaries,
+                ..patched.report
+            })
+        })
+        .collect();
+    let mut report = Report::default();
+    for (target, v) in target_files.iter().zip(result) {
+        match v {
+            Ok(file_report) => {
+                if !file_report.is_empty() {
+                    report.files.insert(target.path.clone(), file_report);
+                }
+            }
+            Err(err) => {
+                error!(
+                    "Failed to process file: file={:?}, err={:?}",
+                    target.path, err
+                );
+                failed_files.insert(target.path.clone());
+            }
+        }
+    }
+
+    // Check patched translation units still compile
+    if checks.verify {
+        let result: Vec<_> = compile_commands
+            .par_iter()
+            .filter(|v| headers_of_compile_commands.contains_key(&v.file))
+            .filter(|v| !failed_files.contains(&v.file))
+            .map(|v| (v, v.check_syntax()))
+            .collect();
+        let mut verification_failures: Vec<(PathBuf, String)> = Vec::new();
+        for (command, v) in result.into_iter() {
+            let diagnostics = match v {
+                Ok(None) => {
+                    report
+                        .files
+                        .entry(command.file.clone())
+                        .or_default()
+                        .verification = Some(Verification {
+                        passed: true,
+                        ..Default::default()
+                    });
+                    continue;
+                }
+                Ok(Some(diagnostics)) => diagnostics,
+                Err(err) => {
+                    error!(
+                        "Failed to verify file: file={:?}, err={:?}",
+                        command.file, err
+                    );
+                    failed_files.insert(command.file.clone());
+                    continue;
+                }
+            };
+            error!(
+                "Patched file does not compile: file={:?}\n{}",
+                command.file, diagnostics
+            );
+            failed_files.insert(command.file.clone());
+            failed_files.extend(
+                headers_of_compile_commands[&command.file]
+                    .iter()
+                    .map(|(header, _)| header.clone()),
+            );
+            verification_failures.push((command.file.clone(), diagnostics));
+        }
+
+        let rolled_back = if checks.rollback_on_failure {
+            let failed: HashSet<&PathBuf> = verification_failures.iter().map(|(v, _)| v).collect();
+            let mut rolled_back = HashSet::new();
+            for file in rollback_files(&headers_of_compile_commands, &failed) {
+                if let Some(original) = originals.get(&file) {
+                    warn!("Roll back file: file={:?}", file);
+                    match fs::write(&file, original) {
+                        Ok(()) => {
+                            rolled_back.insert(file);
+                        }
+                        Err(err) => {
+                            error!("Failed to roll back file: file={:?}, err={:?}", file, err)
+                        }
+                    }
+                }
+            }
+            rolled_back
+        } else {
+            HashSet::new()
+        };
+        for (file, diagnostics) in verification_failures.into_iter() {
+            report.files.entry(file.clone()).or_default().verification = Some(Verification {
+                passed: false,
+                rolled_back: rolled_back.contains(&file),
+                diagnostics,
+            });
+        }
+    }
+
+    // Compare objects of patched translation units with original ones
+    if checks.compare_objects {
+        let result: Vec<_> = compile_commands
+            .par_iter()
+            .enumerate()
+            .filter(|(_, v)| !failed_files.contains(&v.file))
+            .filter_map(|(index, command)| {
+                let original = original_objects.get(&command.file)?;
+                let patched = objects_dir.join(format!("{}.patched.o", index));
+                let result = (|| -> Result<Vec<String>> {
+                    command.compile_object(&patched)?;
+                    Ok(objects::different_sections(
+                        &fs::read(original)?,
+                        &fs::read(&patched)?,
+                    )?)
+                })();
+                Some((command, result))
+            })
+            .collect();
+        for (command, v) in result.into_iter() {
+            match v {
+                Ok(different_sections) => {
+                    if !different_sections.is_empty() {
+                        warn!(
+                            "Object code changed: file={:?}, sections={:?}",
+                            command.file, different_sections
+                        );
+                    }
+                    report
+                        .files
+                        .entry(command.file.clone())
+                        .or_default()
+                        .object_comparison = Some(ObjectComparison {
+                        equivalent: different_sections.is_empty(),
+                        different_sections,
+                    });
+                }
+                Err(err) => error!(
+                    "Failed to compare objects: file={:?}, err={:?}",
+                    command.file, err
+                ),
+            }
+        }
+        let _ = fs::remove_dir_all(&objects_dir);
+    }
+
+    // Record patched files for next run
+    if let Some(mut cache) = cache {
+        for file_path in target_files.iter().map(|v| &v.path) {
+            if failed_files.contains(file_path) {
+                continue;
+            }
+            let hash = match cache::hash_file(file_path) {
+                Ok(hash) => hash,
+                Err(_) => continue,
+            };
+            let headers = headers_of_compile_commands
+                .get(file_path)
+                .map(|v| v.iter().map(|(header, _)| header.clone()).collect())
+                .unwrap_or_default();
+            cache.insert(
+                file_path.clone(),
+                CacheEntry {
+                    hash,
+                    arguments: arguments_of_compile_commands.get(file_path).cloned(),
+                    fingerprint: fingerprint.clone(),
+                    headers,
+                },
+            );
+        }
+        cache.save(&options.cache)?;
+    }
+
+    report.failed_files = failed_files;
+    Ok(report)
+}
+
+/// Find the minimal set of rule edits in `file` which breaks compilation, using the compile
+/// command of `file` itself or the first one including it. `file` is left unchanged.
+pub fn bisect_file(
+    selection: &Selection,
+    rule_options: &RuleOptions,
+    file: &Path,
+) -> Result<BisectOutcome> {
+    let file = canonicalize(file)?;
+    let filter = filter(selection)?;
+    let language_map = language_map(selection)?;
+    let compile_commands = compile_commands(selection, &filter)?;
+
+    let target = compile_commands
+        .iter()
+        .find(|v| v.file == file)
+        .map(|v| (v, v.file_type(&language_map)))
+        .or_else(|| {
+            compile_commands.iter().find_map(|v| {
+                let file_type = v.file_type(&language_map)?;
+                if !file_type.is_source() || !v.included_headers().ok()?.contains(&file) {
+                    return None;
+                }
+                Some((v, Some(language_map.classify_header(&file, file_type))))
+            })
+        });
+    match target {
+        Some((command, file_type)) => {
+            let config = rule_options.rule_config(false)?.for_file(&file);
+            bisect::bisect(command, &file, file_type, &config)
+        }
+        None => Err(Error::OptionError(format!(
+            "No compile command compiles the file: {:?}",
+            file
+        ))),
+    }
+}
+
+/// Edits which `run()` would make in `file` with `rule_options`, without modifying it.
+/// Rules recorded in the marker of `file` are skipped.
+pub fn inspect_file(
+    file: &Path,
+    lang_map: &[String],
+    rule_options: &RuleOptions,
+) -> Result<Vec<RuleEdit>> {
+    let language_map = LanguageMap::new(lang_map).map_err(Error::OptionError)?;
+    let source = fs::read_to_string(file)?;
+    let mut config = rule_options.rule_config(false)?.for_file(file);
+    for rule in marker::applied_rules(&source) {
+        config.rules.remove(&rule);
+    }
+    Ok(
+        patch::all_rule_edits(&source, language_map.classify(file, None), &config)
+            .iter()
+            .map(|(rule, edit)| RuleEdit::new(&source, *rule, edit))
+            .collect(),
+    )
+}
+
+/// Include graphs of translation units in `selection`. Translation units failing `-E` are skipped.
+pub fn include_graphs(selection: &Selection) -> Result<Vec<(CompileCommand, IncludeGraph)>> {
+    let filter = filter(selection)?;
+    let language_map = language_map(selection)?;
+    let compile_commands = compile_commands(selection, &filter)?;
+    let result: Vec<_> = compile_commands
+        .into_par_iter()
+        .filter(|v| v.file_type(&language_map).is_some_and(|v| v.is_source()))
+        .map(|command| {
+            let graph = command.include_graph();
+            (command, graph)
+        })
+        .collect();
+    let mut graphs = Vec::new();
+    for (command, v) in result.into_iter() {
+        match v {
+            Ok(graph) => graphs.push((command, graph)),
+            Err(err) => error!(
+                "Failed to get include graph: file={:?}, err={:?}",
+                command.file, err
+            ),
+        }
+    }
+    Ok(graphs)
+}
+
+/// Merge source files in `selection` into one source.
+/// Translation units failing to be preprocessed are skipped.
+pub fn amalgamate(selection: &Selection) -> Result<Amalgamation> {
+    let filter = filter(selection)?;
+    let language_map = language_map(selection)?;
+    let compile_commands = compile_commands(selection, &filter)?;
+    let result: Vec<_> = compile_commands
+        .into_par_iter()
+        .filter(|v| v.file_type(&language_map).is_some_and(|v| v.is_source()))
+        .map(|command| {
+            let preprocessed = command.run_preprocessor(&["-dI", "-C"]);
+            (command, preprocessed)
+        })
+        .collect();
+    let mut sources = Vec::new();
+    for (command, v) in result.into_iter() {
+        match v {
+            Ok(preprocessed) => {
+                let file = command.directory.join(&command.file);
+                let file = file.canonicalize().unwrap_or(file);
+                sources.push((command, file, preprocessed));
+            }
+            Err(err) => error!(
+                "Failed to preprocess: file={:?}, err={:?}",
+                command.file, err
+            ),
+        }
+    }
+    let units: Vec<Unit> = sources
+        .iter()
+        .map(|(command, file, preprocessed)| Unit {
+            directory: &command.directory,
+            file,
+            preprocessed,
+        })
+        .collect();
+    amalgamation::merge(&units)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::language::{FileKind, Language};
+
+    #[test]
+    fn rollback_files() {
+        let header = |path: &str| {
+            (
+                PathBuf::from(path),
+                FileType::new(Language::C, FileKind::Header),
+            )
+        };
+        let headers_of_compile_commands = HashMap::from([
+            (
+                PathBuf::from("/src/a.c"),
+                vec![header("/src/a.h"), header("/src/common.h")],
+            ),
+            (
+                PathBuf::from("/src/b.c"),
+                vec![header("/src/b.h"), header("/src/common.h")],
+            ),
+            (PathBuf::from("/src/c.c"), vec![header("/src/b.h")]),
+        ]);
+        let a = PathBuf::from("/src/a.c");
+        let b = PathBuf::from("/src/b.c");
+        let c = PathBuf::from("/src/c.c");
+
+        // `common.h` is shared with `b.c` which passed
+        assert_eq!(
+            super::rollback_files(&headers_of_compile_commands, &HashSet::from([&a])),
+            BTreeSet::from([PathBuf::from("/src/a.c"), PathBuf::from("/src/a.h")])
+        );
+        assert_eq!(
+            super::rollback_files(&headers_of_compile_commands, &HashSet::from([&a, &b])),
+            BTreeSet::from([
+                PathBuf::from("/src/a.c"),
+                PathBuf::from("/src/a.h"),
+                PathBuf::from("/src/b.c"),
+                PathBuf::from("/src/common.h")
+            ])
+        );
+        assert_eq!(
+            super::rollback_files(&headers_of_compile_commands, &HashSet::from([&b, &c])),
+            BTreeSet::from([
+                PathBuf::from("/src/b.c"),
+                PathBuf::from("/src/b.h"),
+                PathBuf::from("/src/c.c")
+            ])
+        );
+    }
+}
@@ -0,0 +1,332 @@
+//! Minimal lexer for C/C++ source code which keeps every byte of the input.
+//!
+//! Preprocessor directives are single tokens, so code in macro definitions is not analyzed.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    Identifier,
+    Number,
+    String,
+    Char,
+    Punct,
+    Comment,
+    Directive,
+    Whitespace,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Token<'a> {
+    pub kind: TokenKind,
+    pub text: &'a str,
+    /// Byte offset in the source
+    pub start: usize,
+}
+
+impl<'a> Token<'a> {
+    pub fn end(&self) -> usize {
+        self.start + self.text.len()
+    }
+
+    /// Neither whitespace nor comment
+    pub fn is_significant(&self) -> bool {
+        !matches!(self.kind, TokenKind::Whitespace | TokenKind::Comment)
+    }
+
+    pub fn is(&self, text: &str) -> bool {
+        self.text == text
+    }
+}
+
+const PUNCTS: [&str; 4] = ["...", "::", "->", "##"];
+
+pub fn tokenize(source: &str) -> Vec<Token<'_>> {
+    let bytes = source.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    // Whether only whitespace has been seen since the last newline
+    let mut line_start = true;
+    while i < bytes.len() {
+        let start = i;
+        let c = bytes[i];
+        let kind = if c == b'\n' || c.is_ascii_whitespace() || starts_with(bytes, i, b"\\\n") {
+            while i < bytes.len() {
+                if starts_with(bytes, i, b"\\\n") {
+                    i += 2;
+                } else if bytes[i].is_ascii_whitespace() {
+                    if bytes[i] == b'\n' {
+                        line_start = true;
+                    }
+                    i += 1;
+                } else {
+                    break;
+                }
+            }
+            TokenKind::Whitespace
+        } else if starts_with(bytes, i, b"//") {
+            i = skip_line(bytes, i);
+            TokenKind::Comment
+        } else if starts_with(bytes, i, b"/*") {
+            i = find(bytes, i + 2, b"*/").map_or(bytes.len(), |v| v + 2);
+            TokenKind::Comment
+        } else if c == b'#' && line_start {
+            i = skip_directive(bytes, i);
+            TokenKind::Directive
+        } else if let Some(end) = string_literal(bytes, i) {
+            i = end;
+            if bytes[end - 1] == b'\'' {
+                TokenKind::Char
+            } else {
+                TokenKind::String
+            }
+        } else if c.is_ascii_digit()
+            || (c == b'.' && bytes.get(i + 1).is_some_and(u8::is_ascii_digit))
+        {
+            i += 1;
+            while i < bytes.len() {
+                let c = bytes[i];
+                let exponent_sign =
+                    (c == b'+' || c == b'-') && matches!(bytes[i - 1], b'e' | b'E' | b'p' | b'P');
+                if exponent_sign || c.is_ascii_alphanumeric() || c == b'_' || c == b'.' {
+                    i += 1;
+                } else if c == b'\'' && bytes.get(i + 1).is_some_and(u8::is_ascii_alphanumeric) {
+                    // Digit separator
+                    i += 1;
+                } else {
+                    break;
+                }
+            }
+            TokenKind::Number
+        } else if is_identifier_char(c) {
+            while i < bytes.len() && is_identifier_char(bytes[i]) {
+                i += 1;
+            }
+            TokenKind::Identifier
+        } else {
+            i += PUNCTS
+                .iter()
+                .find(|v| starts_with(bytes, i, v.as_bytes()))
+                .map_or_else(|| char_len(c), |v| v.len());
+            TokenKind::Punct
+        };
+        if kind != TokenKind::Whitespace && kind != TokenKind::Comment {
+            line_start = false;
+        }
+        if kind == TokenKind::Comment && bytes[i - 1] == b'\n' {
+            line_start = true;
+        }
+        tokens.push(Token {
+            kind,
+            text: &source[start..i],
+            start,
+        });
+    }
+    tokens
+}
+
+/// Significant tokens (see `Token::is_significant()`)
+pub fn significant_tokens(source: &str) -> Vec<Token<'_>> {
+    tokenize(source)
+        .into_iter()
+        .filter(Token::is_significant)
+        .collect()
+}
+
+/// Index of the token which closes the bracket at `open`, if any
+pub fn matching_bracket(tokens: &[Token], open: usize) -> Option<usize> {
+    let (open_text, close_text) = match tokens[open].text {
+        "(" => ("(", ")"),
+        "[" => ("[", "]"),
+        "{" => ("{", "}"),
+        "<" => ("<", ">"),
+        _ => return None,
+    };
+    let mut depth = 0;
+    for (i, token) in tokens.iter().enumerate().skip(open) {
+        if token.is(open_text) {
+            depth += 1;
+        } else if token.is(close_text) {
+            depth -= 1;
+            if depth == 0 {
+                return Some(i);
+            }
+        }
+    }
+    None
+}
+
+fn is_identifier_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'_' || c == b'$' || c >= 0x80
+}
+
+fn char_len(c: u8) -> usize {
+    match c {
+        0xf0..=0xff => 4,
+        0xe0..=0xef => 3,
+        0xc0..=0xdf => 2,
+        _ => 1,
+    }
+}
+
+fn starts_with(bytes: &[u8], i: usize, pattern: &[u8]) -> bool {
+    bytes[i..].starts_with(pattern)
+}
+
+fn find(bytes: &[u8], from: usize, pattern: &[u8]) -> Option<usize> {
+    (from..bytes.len()).find(|&i| starts_with(bytes, i, pattern))
+}
+
+/// End of the line including the newline, following line continuations
+fn skip_line(bytes: &[u8], mut i: usize) -> usize {
+    while i < bytes.len() {
+        if starts_with(bytes, i, b"\\\n") {
+            i += 2;
+        } else if bytes[i] == b'\n' {
+            return i + 1;
+        } else {
+            i += 1;
+        }
+    }
+    i
+}
+
+/// End of the directive excluding the newline. Comments and literals in it are skipped over.
+fn skip_directive(bytes: &[u8], mut i: usize) -> usize {
+    while i < bytes.len() {
+        if starts_with(bytes, i, b"\\\n") {
+            i += 2;
+        } else if bytes[i] == b'\n' {
+            return i;
+        } else if starts_with(bytes, i, b"/*") {
+            i = find(bytes, i + 2, b"*/").map_or(bytes.len(), |v| v + 2);
+        } else if starts_with(bytes, i, b"//") {
+            let end = skip_line(bytes, i);
+            return if bytes[end - 1] == b'\n' {
+                end - 1
+            } else {
+                end
+            };
+        } else if let Some(end) = string_literal(bytes, i) {
+            i = end;
+        } else {
+            i += 1;
+        }
+    }
+    i
+}
+
+/// End of the string or character literal starting at `i`, including prefixes such as
+/// `u8` and raw strings
+fn string_literal(bytes: &[u8], i: usize) -> Option<usize> {
+    // Encoding prefix must not be a part of an identifier
+    if i > 0 && is_identifier_char(bytes[i - 1]) {
+        return None;
+    }
+    let mut j = i;
+    for prefix in [&b"u8"[..], b"u", b"U", b"L"] {
+        if starts_with(bytes, j, prefix) {
+            j += prefix.len();
+            break;
+        }
+    }
+    let raw = bytes.get(j) == Some(&b'R') && bytes.get(j + 1) == Some(&b'"');
+    if raw {
+        let open = j + 2;
+        let paren = (open..bytes.len()).find(|&k| bytes[k] == b'(')?;
+        let mut close = vec![b')'];
+        close.extend_from_slice(&bytes[open..paren]);
+        close.push(b'"');
+        return Some(find(bytes, paren, &close).map_or(bytes.len(), |v| v + close.len()));
+    }
+    let quote = *bytes.get(j)?;
+    if quote != b'"' && quote != b'\'' {
+        return None;
+    }
+    let mut k = j + 1;
+    while k < bytes.len() {
+        match bytes[k] {
+            b'\\' => k += 2,
+            b'\n' => return Some(k),
+            c if c == quote => return Some(k + 1),
+            _ => k += 1,
+        }
+    }
+    Some(bytes.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use TokenKind::*;
+
+    fn kinds(source: &str) -> Vec<(TokenKind, &str)> {
+        significant_tokens(source)
+            .iter()
+            .map(|v| (v.kind, v.text))
+            .collect()
+    }
+
+    #[test]
+    fn lossless() {
+        let source =
+            "#include <stdio.h>\n// c\nint main() { /* x */ return u8\"a\\\"b\" R\"x(y)\")x\"; }";
+        let tokens = super::tokenize(source);
+        assert_eq!(
+            tokens
+                .iter()
+                .map(|v| v.text)
+                .collect::<std::string::String>(),
+            source
+        );
+    }
+
+    #[test]
+    fn tokenize() {
+        assert_eq!(
+            kinds("  #define A(x) \\\n  x\nconst char *s = L\"it's\"; // NULL\n"),
+            vec![
+                (Directive, "#define A(x) \\\n  x"),
+                (Identifier, "const"),
+                (Identifier, "char"),
+                (Punct, "*"),
+                (Identifier, "s"),
+                (Punct, "="),
+                (String, "L\"it's\""),
+                (Punct, ";"),
+            ]
+        );
+        assert_eq!(
+            kinds("a = 1'000 + 'x' + 1.5e-3;"),
+            vec![
+                (Identifier, "a"),
+                (Punct, "="),
+                (Number, "1'000"),
+                (Punct, "+"),
+                (Char, "'x'"),
+                (Punct, "+"),
+                (Number, "1.5e-3"),
+                (Punct, ";"),
+            ]
+        );
+        assert_eq!(
+            kinds("std::f(R\"(a\"b)\");"),
+            vec![
+                (Identifier, "std"),
+                (Punct, "::"),
+                (Identifier, "f"),
+                (Punct, "("),
+                (String, "R\"(a\"b)\""),
+                (Punct, ")"),
+                (Punct, ";"),
+            ]
+        );
+        // `#` not at line start is not a directive
+        assert_eq!(kinds("a # b")[1], (Punct, "#"));
+    }
+
+    #[test]
+    fn matching_bracket() {
+        let tokens = significant_tokens("f(a, (b), c) {}");
+        assert_eq!(super::matching_bracket(&tokens, 1), Some(9));
+        assert_eq!(super::matching_bracket(&tokens, 0), None);
+    }
+}